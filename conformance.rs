@@ -0,0 +1,245 @@
+#![crate_name = "dikuchat-conformance"]
+
+/*
+ * Protocol conformance checks.
+ *
+ * Connects to a running server (any implementation of the protocol) and runs a short battery of
+ * scripted checks, printing PASS/FAIL per check. The exit status is non-zero if any check failed.
+ *
+ * Usage: dikuchat-conformance [host] [port]
+ */
+
+use std::io::{TcpStream,BufferedReader,IoResult,EndOfFile};
+use std::io::timer;
+use std::time::Duration;
+use std::os;
+use std::rand;
+
+static TIMEOUT_MS: u64 = 2000;
+
+type Check = fn(&str, u16) -> Result<(), String>;
+
+/* Replies that may come whatever was sent, and which the checks don't look at */
+static UNSOLICITED: &'static [&'static str] = &["JOINED", "LEFT", "RENAMED", "MOTD", "ENDMOTD", "REPLAY",
+                                                "MEMBERS", "TOPIC", "ANONYMOUS"];
+
+struct Conn {
+    reader: BufferedReader<TcpStream>,
+    writer: TcpStream
+}
+
+impl Conn {
+    fn open(host: &str, port: u16) -> Result<Conn, String> {
+        let stream = try!(io(TcpStream::connect(host, port)));
+        let mut rs = stream.clone();
+        rs.set_read_timeout(Some(TIMEOUT_MS));
+        Ok(Conn { reader: BufferedReader::new(rs), writer: stream })
+    }
+
+    fn send(&mut self, line: &str) -> Result<(), String> {
+        self.send_raw(format!("{}\r\n", line).as_slice())
+    }
+
+    fn send_raw(&mut self, raw: &str) -> Result<(), String> {
+        io(self.writer.write_str(raw))
+    }
+
+    /* Read one reply line, insisting on \r\n framing, and return it without the terminator */
+    fn line(&mut self) -> Result<String, String> {
+        let line = try!(io(self.reader.read_line()));
+        if line.as_slice().ends_with("\r\n") {
+            Ok(line.as_slice().slice_to(line.len() - 2).to_string())
+        } else {
+            Err(format!("reply not terminated by \\r\\n: {}", line))
+        }
+    }
+
+    /*
+     * Read the next reply, skipping the presence notifications (JOINED, LEFT, RENAMED) that can
+     * arrive at any time as other clients come and go, the message of the day (MOTD, ENDMOTD)
+     * and REPLAY of recent broadcasts that a server may send on connecting, and the MEMBERS, TOPIC
+     * and ANONYMOUS lines of the rooms a server may join a client to when it first takes a name
+     */
+    fn reply(&mut self) -> Result<String, String> {
+        loop {
+            let line = try!(self.line());
+            let word = line.as_slice().split(' ').next().unwrap_or("");
+            if !UNSOLICITED.contains(&word) {
+                return Ok(line);
+            }
+        }
+    }
+
+    /* Read the WHOREPLY lines of a reply to WHO up to its ENDWHO */
+    fn who(&mut self) -> Result<Vec<String>, String> {
+        let mut lines = Vec::new();
+        loop {
+            let line = try!(self.reply());
+            if line.as_slice() == "ENDWHO" {
+                return Ok(lines);
+            } else if line.as_slice().starts_with("WHOREPLY ") {
+                lines.push(line);
+            } else {
+                return Err(format!("expected WHOREPLY or ENDWHO, got \"{}\"", line));
+            }
+        }
+    }
+
+    fn expect_prefix(&mut self, prefix: &str) -> Result<String, String> {
+        let line = try!(self.reply());
+        if line.as_slice().starts_with(prefix) {
+            Ok(line)
+        } else {
+            Err(format!("expected \"{}...\", got \"{}\"", prefix, line))
+        }
+    }
+}
+
+fn io<T>(r: IoResult<T>) -> Result<T, String> {
+    r.map_err(|e| e.to_string())
+}
+
+/* Names must not collide with whoever else is connected to the server under test */
+fn unique_name(base: &str) -> String {
+    format!("{}{}", base, rand::random::<u16>())
+}
+
+fn who_format(host: &str, port: u16) -> Result<(), String> {
+    let mut c = try!(Conn::open(host, port));
+    try!(c.send("WHO"));
+    /* At least this connection is listed, and every line has its four fields */
+    let lines = try!(c.who());
+    if lines.is_empty() {
+        return Err("no WHOREPLY lines".to_string());
+    }
+    match lines.iter().find(|line| line.as_slice().split(' ').count() != 5) {
+        Some(line) => Err(format!("malformed WHOREPLY: \"{}\"", line)),
+        None       => Ok(())
+    }
+}
+
+fn name_listed(host: &str, port: u16) -> Result<(), String> {
+    let name = unique_name("conf");
+    let mut c = try!(Conn::open(host, port));
+    try!(c.send(format!("NAME {}", name).as_slice()));
+    try!(c.send(format!("WHO {}", name).as_slice()));
+    let lines = try!(c.who());
+    if lines.iter().any(|line| line.as_slice().split(' ').nth(1) == Some(name.as_slice())) {
+        Ok(())
+    } else {
+        Err(format!("{} missing from the reply to WHO {}", name, name))
+    }
+}
+
+fn broadcast_requires_name(host: &str, port: u16) -> Result<(), String> {
+    let mut c = try!(Conn::open(host, port));
+    try!(c.send("BROADCAST hello"));
+    try!(c.expect_prefix("NONAME"));
+    Ok(())
+}
+
+fn broadcast_echo(host: &str, port: u16) -> Result<(), String> {
+    let name = unique_name("conf");
+    let mut c = try!(Conn::open(host, port));
+    try!(c.send(format!("NAME {}", name).as_slice()));
+    try!(c.send("BROADCAST hello there"));
+    let line = try!(c.reply());
+    let expected = format!("FROM {} hello there", name);
+    if line == expected { Ok(()) } else { Err(format!("expected \"{}\", got \"{}\"", expected, line)) }
+}
+
+fn broadcast_fanout(host: &str, port: u16) -> Result<(), String> {
+    let name = unique_name("conf");
+    let mut a = try!(Conn::open(host, port));
+    let mut b = try!(Conn::open(host, port));
+    try!(a.send(format!("NAME {}", name).as_slice()));
+    try!(a.send("BROADCAST fanout"));
+    let line = try!(b.reply());
+    let expected = format!("FROM {} fanout", name);
+    if line == expected { Ok(()) } else { Err(format!("expected \"{}\", got \"{}\"", expected, line)) }
+}
+
+fn unknown_command(host: &str, port: u16) -> Result<(), String> {
+    let mut c = try!(Conn::open(host, port));
+    try!(c.send("FROBNICATE now"));
+    try!(c.expect_prefix("ERROR "));
+    Ok(())
+}
+
+fn empty_name(host: &str, port: u16) -> Result<(), String> {
+    let mut c = try!(Conn::open(host, port));
+    try!(c.send("NAME"));
+    try!(c.expect_prefix("ERROR "));
+    Ok(())
+}
+
+fn pipelined_commands(host: &str, port: u16) -> Result<(), String> {
+    let mut c = try!(Conn::open(host, port));
+    try!(c.send_raw("WHO\r\nWHO\r\n"));
+    try!(c.who());
+    try!(c.who());
+    Ok(())
+}
+
+fn split_command(host: &str, port: u16) -> Result<(), String> {
+    let mut c = try!(Conn::open(host, port));
+    try!(c.send_raw("WH"));
+    timer::sleep(Duration::milliseconds(100));
+    try!(c.send_raw("O\r\n"));
+    try!(c.who());
+    Ok(())
+}
+
+fn quit_closes(host: &str, port: u16) -> Result<(), String> {
+    let mut c = try!(Conn::open(host, port));
+    try!(c.send("QUIT"));
+    match c.reader.read_line() {
+        Err(ref e) if e.kind == EndOfFile => Ok(()),
+        Err(e)   => Err(format!("expected end of stream, got {}", e)),
+        Ok(line) => Err(format!("expected end of stream, got \"{}\"", line))
+    }
+}
+
+fn main() {
+    let args = os::args();
+    let host = if args.len() > 1 { args[1].clone() } else { "127.0.0.1".to_string() };
+    let port = if args.len() > 2 {
+        match from_str::<u16>(args[2].as_slice()) {
+            Some(p) => p,
+            None    => {
+                println!("Invalid port: {}", args[2]);
+                os::set_exit_status(2);
+                return;
+            }
+        }
+    } else { 8090 };
+
+    let checks: Vec<(&'static str, Check)> = vec![
+        ("who-format",              who_format as Check),
+        ("name-listed",             name_listed as Check),
+        ("broadcast-requires-name", broadcast_requires_name as Check),
+        ("broadcast-echo",          broadcast_echo as Check),
+        ("broadcast-fanout",        broadcast_fanout as Check),
+        ("unknown-command",         unknown_command as Check),
+        ("empty-name",              empty_name as Check),
+        ("pipelined-commands",      pipelined_commands as Check),
+        ("split-command",           split_command as Check),
+        ("quit-closes",             quit_closes as Check),
+    ];
+
+    let mut failed = 0u;
+    for &(name, check) in checks.iter() {
+        match check(host.as_slice(), port) {
+            Ok(())   => println!("PASS {}", name),
+            Err(why) => {
+                println!("FAIL {}: {}", name, why);
+                failed += 1;
+            }
+        }
+    }
+
+    println!("{} of {} checks passed", checks.len() - failed, checks.len());
+    if failed > 0 {
+        os::set_exit_status(1);
+    }
+}
@@ -1,157 +1,195 @@
-extern crate uuid;
+extern crate dikuchat;
 
-use std::io::{TcpStream,TcpListener,Acceptor,Listener};
-use std::sync::{Arc,RWLock};
-use std::collections::HashMap;
-use uuid::Uuid;
+use std::os;
+use std::io::signal::{Listener,Interrupt};
+use std::from_str::FromStr;
+use dikuchat::{Server,Config};
 
-enum Method {
-    Quit,
-    Who,
-    Name(String),
-    Broadcast(String)
-}
+/* How often each soak client broadcasts, unless --soak-interval says otherwise */
+static SOAK_INTERVAL_MS: i64 = 1000;
 
-/*
- * A clients data structure. Essentially a shared hash map, so it is wrapped in an RWLock.
- *
- * Each client is assigned an id (Uuid) and stores a pair: Its broadcast sending channel and name.
- */
-type Clients = Arc<RWLock<HashMap<Uuid, (Sender<(String, String)>, String)>>>;
+/* Command line flags that override a setting from the configuration file, and the setting */
+static CONFIG_FLAGS: &'static [(&'static str, &'static str)] = &[
+    ("--listen",       "address"),
+    ("--port",         "port"),
+    ("--max-clients",  "max_clients"),
+    ("--max-line",     "max_line"),
+    ("--idle-timeout", "idle_timeout"),
+    ("--keepalive", "keepalive_interval"),
+    ("--keepalive-grace", "keepalive_grace"),
+    ("--outgoing-queue", "outgoing_queue"),
+    ("--slow-clients", "slow_clients"),
+    ("--log-level", "log_level"),
+    ("--mirror", "mirror_port"),
+    ("--http", "http_port"),
+    ("--websocket", "websocket_port"),
+    ("--record", "record_dir")
+];
 
-/*
- * Input processing. This is written to take advantage of (a) Rust's iterators and (b) pattern matching.
- */
-fn process_input(inp: &[u8]) -> Option<Method> {
-    let mut it = inp.iter().peekable();
-    let m: Vec<u8> = it.take_while(|&c| *c != b' '
-                                   &&   *c != b'\r'
-                                   &&   it.peek().map_or(false, |&c2| *c2 != b'\n'))
-                       .map(|&c| c)
-                       .collect();
-    
-    match m.into_ascii().into_string().as_slice() {
-        "QUIT" => Some(Quit),
-        "WHO"  => Some(Who),
-        "NAME" => {
-            let name: Vec<u8> = it.skip(5)
-                                  .take_while(|&c| *c != b'\r' && it.peek().map_or(false, |&c2| *c2 != b'\n'))
-                                  .map(|&c| c)
-                                  .collect();
-            if name.is_empty() { None } else { Some(Name(name.into_ascii().into_string())) }
-        },
-        "BROADCAST" => Some(Broadcast(it.skip(10)
-                                        .take_while(|&c| *c != b'\r' && it.peek().map_or(false, |&c2| *c2 != b'\n'))
-                                        .map(|&c| c)
-                                        .collect::<Vec<u8>>()
-                                        .into_ascii()
-                                        .into_string())),
-        _      => None
+fn number<T: FromStr>(flag: &str, value: &str) -> Option<T> {
+    let n = from_str(value);
+    if n.is_none() {
+        println!("Invalid value for {}: {}", flag, value);
+        os::set_exit_status(2);
     }
+    n
 }
 
-/*
- * The client receives
- *
- * id: To be able to find itself in the client structure
- * stream: The TCP stream to read from
- * bcast: A receiver to receive broadcast messages
- */
-fn handle_client(id: Uuid, mut stream: TcpStream, clients: Clients, bcast: Receiver<(String, String)>) {
-    let mut buffer = [0u8, ..1024*16];
-    let mut sc = stream.clone();
-    let mut name = "".to_string();
-    let (tx, rx) = channel();
-
-    /*
-     * Spawn reader
-     *
-     * 1) Parses received messages
-     * 2) Quits when the (a) QUIT message is received or (b) a read error is detected
-     */
-    spawn(proc() {
-        loop {
-            match sc.read(buffer) {
-                Ok(n)  => match process_input(buffer.slice(0,n-2)) {
-                    Some(Quit) => {
-                        tx.send(Quit);
-                        break;
+fn main() {
+    let args = os::args();
+    let mut config_file = None;
+    let mut overrides = Vec::new();
+    let mut soak = 0u;
+    let mut soak_interval_ms = SOAK_INTERVAL_MS;
+    let mut i = 1;
+    while i < args.len() {
+        let flag = args[i].as_slice();
+        let has_value = i + 1 < args.len();
+        match flag {
+            "--config" if has_value => {
+                config_file = Some(Path::new(args[i+1].as_slice()));
+                i += 1;
+            },
+            "--trace-protocol" => overrides.push(("trace_protocol", "true")),
+            "--suffix-names" => overrides.push(("suffix_names", "true")),
+            "--soak" if has_value => {
+                match number(flag, args[i+1].as_slice()) {
+                    Some(n) => soak = n,
+                    None    => return
+                }
+                i += 1;
+            },
+            "--soak-interval" if has_value => {
+                match number(flag, args[i+1].as_slice()) {
+                    Some(ms) if ms > 0 => soak_interval_ms = ms,
+                    Some(_) => {
+                        println!("Invalid value for {}: {}", flag, args[i+1]);
+                        os::set_exit_status(2);
+                        return;
                     },
-                    Some(m) => tx.send(m),
-                    None    => {
-                        sc.write(b"ERROR ").unwrap();
-                        sc.write(buffer.slice(0, n)).unwrap();
-                    }
-                },
-                Err(e) => {
-                    println!("Received {}. Quitting.", e);
-                    tx.send(Quit);
-                    break;
+                    None => return
                 }
-            }
-        }
-    });
-
-    loop {
-        select! {
-            meth = rx.recv() => match meth {
-                Quit => {
-                    clients.write().pop(&id).unwrap();
-                    drop(stream);
-                    break;
-                },
-                Who => {
-                    /* Write all user names to stream */
-                    stream.write(b"NAMES").unwrap();
-                    for &(_, ref name) in clients.read().values() {
-                        stream.write(b" ").unwrap();
-                        stream.write_str(name.as_slice()).unwrap();
+                i += 1;
+            },
+            "--dump-protocol" if has_value => {
+                if !dikuchat::dump_protocol(args[i+1].as_slice()) {
+                    println!("Unknown protocol format: {} (json or markdown)", args[i+1]);
+                    os::set_exit_status(2);
+                }
+                return;
+            },
+            "--replay" if has_value => {
+                match dikuchat::replay(&Path::new(args[i+1].as_slice())) {
+                    Ok(()) => (),
+                    Err(e) => {
+                        println!("Cannot replay {}: {}", args[i+1], e);
+                        os::set_exit_status(1);
                     }
-                    stream.write(b"\r\n").unwrap();
-                },
-                Name(new_name) => {
-                    name = new_name.clone();
-                    let mut c = clients.write();
-                    let (ch, _) = c.pop(&id).unwrap();
-                    c.insert(id, (ch, new_name));
-                },
-                Broadcast(msg) => if name.is_empty() {
-                    stream.write(b"NONAME\r\n").unwrap();
-                } else {
-                    for &(ref client, _) in clients.read().values() {
-                        client.send((name.clone(), msg.clone()));;
-                    }   
                 }
+                return;
             },
-            (name, msg) = bcast.recv() => {
-                stream.write(b"FROM ").unwrap();
-                stream.write_str(name.as_slice()).unwrap();
-                stream.write(b" ").unwrap();
-                stream.write_str(msg.as_slice()).unwrap();
-                stream.write(b"\r\n").unwrap();
+            _ if has_value && CONFIG_FLAGS.iter().any(|&(f, _)| f == flag) => {
+                let &(_, key) = CONFIG_FLAGS.iter().find(|&&(f, _)| f == flag).unwrap();
+                overrides.push((key, args[i+1].as_slice()));
+                i += 1;
+            },
+            other => {
+                println!("Unknown argument: {}", other);
+                os::set_exit_status(2);
+                return;
             }
         }
+        i += 1;
     }
-}
 
-fn main() {
-    let mut acpt = TcpListener::bind("127.0.0.1", 8090).listen().unwrap();
-    acpt.set_timeout(None);
-
-    let clients = Arc::new(RWLock::new(HashMap::new()));
-    loop {
-        match acpt.accept() {
-            Ok(st) => {
-                let (tx, rx) = channel();
-                let id = Uuid::new_v4();
-                clients.write().insert(id, (tx, "".to_string()));
-                
-                let clients_cln = clients.clone();
-                spawn(proc() handle_client(id, st, clients_cln, rx))
-            },
+    let mut config = match config_file {
+        Some(ref path) => match Config::load(path) {
+            Ok(config) => config,
+            Err(e)     => {
+                println!("Cannot load configuration: {}", e);
+                os::set_exit_status(2);
+                return;
+            }
+        },
+        None => Config::new()
+    };
+    for &(key, value) in overrides.iter() {
+        match config.set(key, value) {
+            Ok(()) => (),
             Err(e) => {
                 println!("{}", e);
+                os::set_exit_status(2);
+                return;
             }
         }
     }
+
+    match config.check() {
+        Ok(()) => (),
+        Err(e) => {
+            println!("Conflicting settings: {}", e);
+            os::set_exit_status(2);
+            return;
+        }
+    }
+
+    let (address, port) = (config.address.clone(), config.port);
+    let (mirror_port, http_port, websocket_port) = (config.mirror_port, config.http_port, config.websocket_port);
+    match config.record_dir {
+        Some(ref dir) => println!("Recording all client input to {}", dir.display()),
+        None => ()
+    }
+    let server = match Server::with_config(config) {
+        Ok(server) => server,
+        Err(e)     => {
+            println!("Cannot listen on {}:{}: {}", address, port, e);
+            os::set_exit_status(1);
+            return;
+        }
+    };
+
+    match mirror_port {
+        Some(port) => match server.mirror(port) {
+            Ok(()) => println!("Mirroring broadcasts read-only on {}:{}", address, port),
+            Err(e) => println!("Cannot listen for mirrors on {}:{}: {}", address, port, e)
+        },
+        None => ()
+    }
+    match http_port {
+        Some(port) => match server.http(port) {
+            Ok(()) => println!("Serving room event streams on http://{}:{}/rooms/<room>", address, port),
+            Err(e) => println!("Cannot listen for HTTP on {}:{}: {}", address, port, e)
+        },
+        None => ()
+    }
+
+    match websocket_port {
+        Some(port) => match server.websocket(port) {
+            Ok(()) => println!("Serving WebSocket clients on ws://{}:{}/", address, port),
+            Err(e) => println!("Cannot listen for WebSocket clients on {}:{}: {}", address, port, e)
+        },
+        None => ()
+    }
+
+    if soak > 0 {
+        println!("Starting {} soak clients, each broadcasting every {}ms", soak, soak_interval_ms);
+        server.soak(soak, soak_interval_ms);
+    }
+
+    /* Ctrl-C shuts down gracefully: clients are told SERVERCLOSING and run() returns */
+    let mut signals = Listener::new();
+    match signals.register(Interrupt) {
+        Ok(()) => {
+            let stopper = server.clone();
+            spawn(proc() {
+                if signals.rx.recv_opt().is_ok() {
+                    stopper.shutdown();
+                }
+            });
+        },
+        Err(e) => println!("Cannot catch SIGINT, Ctrl-C will drop everyone: {}", e)
+    }
+
+    server.console();
+    server.run();
 }
@@ -0,0 +1,4707 @@
+#![crate_name = "dikuchat"]
+#![crate_type = "lib"]
+
+/*
+ * The dikuchat server, as a library. A Server listens on an address and serves clients until it is
+ * shut down; the dikuchat binary is a thin command line wrapper around one, and programs that want
+ * a chat server of their own (or integration tests) can embed one the same way.
+ */
+
+extern crate time;
+extern crate serialize;
+extern crate rustc_back;
+
+use std::io::{TcpStream,TcpListener,TcpAcceptor,Acceptor,Listener,File,Writer,IoResult,IoError,BufferedReader};
+use std::io::{InvalidInput,standard_error};
+use std::io::{EndOfFile,ShortWrite,ResourceUnavailable,TimedOut,BrokenPipe};
+use std::io::{timer,stdin};
+use std::io::net::ip::{IpAddr,SocketAddr};
+use std::sync::{Arc,RWLock,Mutex};
+use std::comm::{Full,RecvDisconnected};
+use std::sync::atomic::{AtomicUint,AtomicBool,INIT_ATOMIC_BOOL,INIT_ATOMIC_UINT,SeqCst,Relaxed};
+use std::collections::{HashMap,HashSet,RingBuf,TreeMap};
+use std::time::Duration;
+use std::fmt;
+use std::from_str::FromStr;
+use std::iter::range_step_inclusive;
+use std::rand::{task_rng,Rng};
+use serialize::json;
+use serialize::hex::ToHex;
+use serialize::base64::{ToBase64,STANDARD};
+use rustc_back::sha2::{Digest,Sha256};
+
+/* How often, and how far apart, a client write that would block is retried before giving up */
+static WRITE_RETRIES: uint = 5;
+static WRITE_RETRY_MS: i64 = 100;
+
+/*
+ * How many parsed commands may wait between a client's reader and its handler. When the queue is
+ * full the reader blocks, stops reading, and the client is slowed down by TCP flow control.
+ */
+static COMMAND_QUEUE: uint = 32;
+
+/* How many events may wait to be written to a client, unless the server is told otherwise */
+static OUTGOING_QUEUE: uint = 256;
+
+/* The longest line a client may send, not counting the \r\n, unless the server is told otherwise */
+pub static MAX_LINE: uint = 4096;
+
+/* The longest name, in characters rather than bytes so names in any script get the same room */
+static MAX_NAME: uint = 32;
+
+/* How many broadcasts are kept for HISTORY, and how many a new client is sent, unless the server is told otherwise */
+static HISTORY_SIZE: uint = 100;
+static HISTORY_ON_CONNECT: uint = 10;
+
+/*
+ * Flood protection, unless the server is told otherwise: tokens per second, the most that can be
+ * saved up, and throttled commands before disconnecting
+ */
+static RATE_LIMIT: f64 = 5.0;
+static RATE_BURST: f64 = 10.0;
+static THROTTLE_STRIKES: uint = 20;
+
+/* How FROM, PRIVFROM and SAYFROM lines look, unless the server is told otherwise. See message_line */
+static FORMAT_FROM: &'static str = "FROM {name} {text}";
+static FORMAT_PRIVFROM: &'static str = "PRIVFROM {name} {text}";
+static FORMAT_SAYFROM: &'static str = "SAYFROM {room} {name} {text}";
+static SERVER_TAG: &'static str = "dikuchat";
+
+/* Seconds a client has to answer a keepalive PING, unless the server is told otherwise */
+static KEEPALIVE_GRACE: u64 = 30;
+
+/* How long run() waits for clients to be sent SERVERCLOSING before returning anyway */
+static SHUTDOWN_GRACE_MS: i64 = 5000;
+
+/* How many of the most recent handler timings are kept per verb for the latency percentiles */
+static LATENCY_SAMPLES: uint = 1000;
+
+/*
+ * Bytes read from and written to clients. These are counted where the bytes are read and written,
+ * for every server in the process.
+ */
+static BYTES_IN: AtomicUint = INIT_ATOMIC_UINT;
+static BYTES_OUT: AtomicUint = INIT_ATOMIC_UINT;
+
+/* Set by --trace-protocol: print every protocol line going in or out */
+static TRACE_PROTOCOL: AtomicBool = INIT_ATOMIC_BOOL;
+
+pub fn set_trace_protocol(on: bool) {
+    TRACE_PROTOCOL.store(on, SeqCst);
+}
+
+/*
+ * Logging. Diagnostics go through log!, which puts the time and level first and, for lines about a
+ * client, its id and address in brackets, and hands the line to the sink: stdout, unless a program
+ * embedding the server sets its own with set_log_sink before starting it. Lines less severe than
+ * the log_level of the last server created are skipped.
+ */
+#[deriving(Clone, PartialEq, PartialOrd)]
+pub enum Level {
+    LogError,
+    LogWarn,
+    LogInfo,
+    LogDebug
+}
+
+impl fmt::Show for Level {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.pad(match *self { LogError => "ERROR", LogWarn => "WARN", LogInfo => "INFO", LogDebug => "DEBUG" })
+    }
+}
+
+impl FromStr for Level {
+    fn from_str(s: &str) -> Option<Level> {
+        match s {
+            "error" => Some(LogError),
+            "warn"  => Some(LogWarn),
+            "info"  => Some(LogInfo),
+            "debug" => Some(LogDebug),
+            _       => None
+        }
+    }
+}
+
+static LOG_LEVEL: AtomicUint = INIT_ATOMIC_UINT;
+static mut LOG_SINK: fn(&str) = stdout_sink;
+
+fn stdout_sink(line: &str) {
+    println!("{}", line);
+}
+
+pub fn set_log_level(level: Level) {
+    LOG_LEVEL.store(level as uint, SeqCst);
+}
+
+/* Where log lines go instead of stdout. Set it before starting a server, as it isn't synchronized */
+pub fn set_log_sink(sink: fn(&str)) {
+    unsafe { LOG_SINK = sink }
+}
+
+fn log_enabled(level: Level) -> bool {
+    level as uint <= LOG_LEVEL.load(Relaxed)
+}
+
+fn write_log(level: Level, client: &str, message: String) {
+    let time = time::now_utc().rfc3339();
+    let line = if client.is_empty() {
+        format!("{} {:<5} {}", time, level, message)
+    } else {
+        format!("{} {:<5} [{}] {}", time, level, client, message)
+    };
+    unsafe { LOG_SINK(line.as_slice()) }
+}
+
+/* What log lines about a client are tagged with: its id and the address it connected from */
+fn client_tag(id: &Id, peer: Option<IpAddr>) -> String {
+    format!("{} {}", id, peer.map_or("-".to_string(), |ip| ip.to_string()))
+}
+
+/* log!(level, client tag or "", format, args...) */
+macro_rules! log(
+    ($level:expr, $client:expr, $($arg:tt)*) => (
+        if log_enabled($level) { write_log($level, $client, format!($($arg)*)) }
+    )
+)
+
+#[deriving(Show)]
+pub enum Method {
+    Quit,
+    Who(Option<String>),
+    Whox,
+    Name(String),
+    Broadcast(String),
+    /* Private message: recipient name and text */
+    Msg(String, String),
+    /* Rooms */
+    Join(String),
+    Part(String),
+    List,
+    Say(String, String),
+    /* A room's topic, or set it to the text */
+    Topic(String, Option<String>),
+    /* Utility commands */
+    Roll(uint, uint),
+    Choose(Vec<String>),
+    Seen(String),
+    /* Connection quality: LAG starts a PING that the client answers with PONG */
+    Lag,
+    Pong(String),
+    /* Moderation: become an operator with the password, then kick (name and reason) or ban */
+    Oper(String),
+    KickUser(String, String),
+    Ban(String),
+    /* Accounts: name and password */
+    Register(String, String),
+    Login(String, String),
+    /* The most recent broadcasts, all of those kept if no count is given */
+    History(Option<uint>),
+    /* Usage of every command the client may use, or of one of them */
+    Help(Option<String>),
+    /* Switch the connection to TEXT or JSON */
+    Proto(String),
+    /* Operators: make the first name lead to the second room; rename the first room to the second */
+    AliasRoom(String, String),
+    RenameRoom(String, String),
+    /* Operators: reread the MOTD file */
+    Rehash,
+    /* Operators: switch a room's anonymous mode on or off */
+    Anonymous(String, bool)
+}
+
+impl Method {
+    /* The protocol verb, as it appears on the wire */
+    pub fn verb(&self) -> &'static str {
+        match *self {
+            Quit         => "QUIT",
+            Who(_)       => "WHO",
+            Whox         => "WHOX",
+            Name(_)      => "NAME",
+            Broadcast(_) => "BROADCAST",
+            Msg(..)      => "MSG",
+            Join(_)      => "JOIN",
+            Part(_)      => "PART",
+            List         => "LIST",
+            Say(..)      => "SAY",
+            Topic(..)    => "TOPIC",
+            Roll(..)     => "ROLL",
+            Choose(_)    => "CHOOSE",
+            Seen(_)      => "SEEN",
+            Lag          => "LAG",
+            Pong(_)      => "PONG",
+            Oper(_)      => "OPER",
+            KickUser(..) => "KICK",
+            Ban(_)       => "BAN",
+            Register(..) => "REGISTER",
+            Login(..)    => "LOGIN",
+            History(_)   => "HISTORY",
+            Help(_)      => "HELP",
+            Proto(_)     => "PROTO",
+            AliasRoom(..)  => "ALIAS",
+            RenameRoom(..) => "RENAMEROOM",
+            Rehash         => "REHASH",
+            Anonymous(..)  => "ANONYMOUS"
+        }
+    }
+
+    /* What the command costs in flood protection tokens. Fanning out to everyone costs the most */
+    fn cost(&self) -> f64 {
+        match *self {
+            Quit | Pong(_)                                 => 0.0,
+            Who(_) | Whox | List | Seen(_) | Lag | History(_) | Help(_) | Topic(_, None) => 0.5,
+            Broadcast(_)                                   => 2.0,
+            _                                              => 1.0
+        }
+    }
+}
+
+/*
+ * The protocol as documented by --dump-protocol. Every verb process_input understands and every
+ * line the server sends belongs in these tables, so keep them in step with Method and the handler.
+ */
+#[deriving(Encodable)]
+struct VerbDoc {
+    verb: &'static str,
+    args: &'static str,
+    summary: &'static str,
+    replies: &'static [&'static str],
+    /* Only for operators, so HELP leaves it out for everyone else */
+    oper: bool
+}
+
+#[deriving(Encodable)]
+struct ReplyDoc {
+    reply: &'static str,
+    args: &'static str,
+    summary: &'static str
+}
+
+#[deriving(Encodable)]
+struct ProtocolDoc {
+    verbs: &'static [VerbDoc],
+    replies: &'static [ReplyDoc]
+}
+
+static VERBS: &'static [VerbDoc] = &[
+    VerbDoc { verb: "QUIT", args: "",
+              summary: "Disconnect from the server.",
+              replies: &[], oper: false },
+    VerbDoc { verb: "WHO", args: "[<pattern>]",
+              summary: "List the connected clients one per line, or only those whose names match the pattern, in which * matches any run of characters and ? any one.",
+              replies: &["WHOREPLY", "ENDWHO"], oper: false },
+    VerbDoc { verb: "WHOX", args: "",
+              summary: "List the named clients one per line, sorted by name, for scripts.",
+              replies: &["WHOX", "ENDWHOX"], oper: false },
+    VerbDoc { verb: "NAME", args: "<name>",
+              summary: "Set or change your name, of at most 32 characters. Names are unique regardless of case; yours keeps the case you gave.",
+              replies: &["NAMEINUSE", "NAMERESERVED", "NAMED", "MEMBERS", "TOPIC", "ANONYMOUS", "ERROR"], oper: false },
+    VerbDoc { verb: "BROADCAST", args: "<text>",
+              summary: "Send a message to every connected client, yourself included.",
+              replies: &["FROM", "NONAME"], oper: false },
+    VerbDoc { verb: "MSG", args: "<name> <text>",
+              summary: "Send a private message to the named client only.",
+              replies: &["PRIVFROM", "NOSUCHUSER", "NONAME"], oper: false },
+    VerbDoc { verb: "JOIN", args: "<room>",
+              summary: "Join a room, creating it if nobody is in it. Room names are matched regardless of case.",
+              replies: &["MEMBERS", "TOPIC", "ANONYMOUS", "NOTICE"], oper: false },
+    VerbDoc { verb: "PART", args: "<room>",
+              summary: "Leave a room. Empty rooms disappear.",
+              replies: &["PARTED", "NOTINROOM"], oper: false },
+    VerbDoc { verb: "LIST", args: "",
+              summary: "List all rooms and how many members each has.",
+              replies: &["ROOM", "ENDROOMS"], oper: false },
+    VerbDoc { verb: "SAY", args: "<room> <text>",
+              summary: "Send a message to the members of a room you are in, yourself included.",
+              replies: &["SAYFROM", "NOTINROOM", "NONAME"], oper: false },
+    VerbDoc { verb: "TOPIC", args: "<room> [<text>]",
+              summary: "Show a room's topic, or set it if you are in the room. Its members, and whoever joins it later, are told the new topic.",
+              replies: &["TOPIC", "NOTOPIC", "TOPICCHANGED", "NOSUCHROOM", "NOTINROOM", "NONAME"], oper: false },
+    VerbDoc { verb: "ROLL", args: "<dice>",
+              summary: "Roll dice written NdM: N dice with M sides each (at most 100 dice of up to 1000 sides).",
+              replies: &["ROLLED", "ERROR"], oper: false },
+    VerbDoc { verb: "CHOOSE", args: "<option>|<option>[|...]",
+              summary: "Pick one of the options at random.",
+              replies: &["CHOSE", "ERROR"], oper: false },
+    VerbDoc { verb: "SEEN", args: "<name>",
+              summary: "When the named client was last active, and what they did.",
+              replies: &["SEEN", "NOTSEEN"], oper: false },
+    VerbDoc { verb: "LAG", args: "",
+              summary: "Measure the round trip to the server. Answer the PING that follows with PONG.",
+              replies: &["PING", "LAG"], oper: false },
+    VerbDoc { verb: "PONG", args: "<token>",
+              summary: "Answer a PING, echoing its token.",
+              replies: &[], oper: false },
+    VerbDoc { verb: "OPER", args: "<password>",
+              summary: "Become an operator, if the password is the server's operator password.",
+              replies: &["OPERED", "BADPASSWORD"], oper: false },
+    VerbDoc { verb: "KICK", args: "<name> [<reason>]",
+              summary: "Operators only: disconnect the named client, telling them the reason.",
+              replies: &["NOTOPER", "NOSUCHUSER"], oper: true },
+    VerbDoc { verb: "BAN", args: "<name>|<ip>",
+              summary: "Operators only: refuse connections from the address, or the named client's address, until the server restarts. Whoever is connected from there is disconnected.",
+              replies: &["BANNED", "NOTOPER", "NOSUCHUSER"], oper: true },
+    VerbDoc { verb: "REGISTER", args: "<name> <password>",
+              summary: "Create an account for a name, which reserves it, and log in to it.",
+              replies: &["REGISTERED", "ACCOUNTEXISTS", "NAMEINUSE", "NAMERESERVED", "NOACCOUNTS", "MEMBERS", "TOPIC", "ANONYMOUS"], oper: false },
+    VerbDoc { verb: "LOGIN", args: "<name> <password>",
+              summary: "Log in to an account, taking its name.",
+              replies: &["LOGGEDIN", "BADLOGIN", "NAMEINUSE", "MEMBERS", "TOPIC", "ANONYMOUS"], oper: false },
+    VerbDoc { verb: "HISTORY", args: "[<count>]",
+              summary: "Replay the most recent broadcasts, oldest first: the last count of them, or all the server keeps.",
+              replies: &["REPLAY", "ENDHISTORY"], oper: false },
+    VerbDoc { verb: "ALIAS", args: "<alias> <room>",
+              summary: "Operators only: make JOIN, PART and SAY on the alias go to the room instead. ALIAS <room> <room> removes an alias.",
+              replies: &["ALIASED", "ROOMEXISTS", "NOTOPER"], oper: true },
+    VerbDoc { verb: "RENAMEROOM", args: "<room> <new>",
+              summary: "Operators only: rename a room, keeping its members. The old name becomes an alias of the new one.",
+              replies: &["ROOMRENAMED", "NOSUCHROOM", "ROOMEXISTS", "NOTOPER"], oper: true },
+    VerbDoc { verb: "REHASH", args: "",
+              summary: "Operators only: reread the MOTD file, so clients connecting from then on get the new message of the day.",
+              replies: &["REHASHED", "REHASHFAILED", "NOTOPER"], oper: true },
+    VerbDoc { verb: "ANONYMOUS", args: "<room> <ON|OFF>",
+              summary: "Operators only: show what is said in the room as from ANON instead of its sender, or stop. Only the server log records who said it.",
+              replies: &["ANONYMOUS", "NOSUCHROOM", "NOTOPER", "ERROR"], oper: true },
+    VerbDoc { verb: "PROTO", args: "<TEXT|JSON>",
+              summary: "Switch this connection to the text protocol or to JSON: one object per line, with the command or reply in type and its arguments under the names given here.",
+              replies: &["PROTO", "ERROR"], oper: false },
+    VerbDoc { verb: "HELP", args: "[<verb>]",
+              summary: "List the commands you may use with their arguments, or describe one of them. Disabled commands are left out.",
+              replies: &["USAGE", "HELPTEXT", "ENDHELP", "NOSUCHVERB"], oper: false }
+];
+
+static REPLIES: &'static [ReplyDoc] = &[
+    ReplyDoc { reply: "WHOREPLY", args: "<name>|* <idle-seconds> <address>|- <connected|spoke|unnamed>",
+               summary: "One connected client, in reply to WHO: its name (* if it has none), the seconds since its last activity, its address and that activity." },
+    ReplyDoc { reply: "ENDWHO", args: "",
+               summary: "The end of the reply to WHO." },
+    ReplyDoc { reply: "WHOX", args: "<name>\\t<connected|spoke>\\t<room>[,...]|-\\t<idle-seconds>\\t<connection>",
+               summary: "One named client, in reply to WHOX: its last activity, its rooms, the seconds since that activity, and its connection number. The fields are separated by tabs." },
+    ReplyDoc { reply: "ENDWHOX", args: "",
+               summary: "The end of the reply to WHOX." },
+    ReplyDoc { reply: "FROM", args: "<name> <text>",
+               summary: "A broadcast from the named client." },
+    ReplyDoc { reply: "REPLAY", args: "<timestamp> <name> <text>",
+               summary: "An earlier broadcast, sent at timestamp (seconds since the epoch). In reply to HISTORY, and the last few are sent on connecting." },
+    ReplyDoc { reply: "ENDHISTORY", args: "",
+               summary: "The end of the reply to HISTORY." },
+    ReplyDoc { reply: "PRIVFROM", args: "<name> <text>",
+               summary: "A private message from the named client." },
+    ReplyDoc { reply: "MEMBERS", args: "<room> [<name> ...]",
+               summary: "The names of a room's members, in reply to JOIN, and for each room you are joined to on taking a name or logging in." },
+    ReplyDoc { reply: "PARTED", args: "<room>",
+               summary: "You have left the room." },
+    ReplyDoc { reply: "NOTINROOM", args: "<room>",
+               summary: "You are not a member of that room." },
+    ReplyDoc { reply: "ROOM", args: "<room> <members>",
+               summary: "One room and its number of members, in reply to LIST." },
+    ReplyDoc { reply: "ENDROOMS", args: "",
+               summary: "The end of the reply to LIST." },
+    ReplyDoc { reply: "SAYFROM", args: "<room> <name> <text>",
+               summary: "A message to a room from the named member." },
+    ReplyDoc { reply: "TOPIC", args: "<room> <name> <text>",
+               summary: "A room's topic and who set it, in reply to TOPIC and after MEMBERS when you join a room that has one." },
+    ReplyDoc { reply: "ANONYMOUS", args: "<room> <ON|OFF>",
+               summary: "Whether what is said in the room is shown as from ANON. Sent to its members when an operator changes it, and after MEMBERS when you join an anonymous room." },
+    ReplyDoc { reply: "NOTOPIC", args: "<room>",
+               summary: "Nobody has set the room's topic." },
+    ReplyDoc { reply: "TOPICCHANGED", args: "<room> <name> <text>",
+               summary: "A member of a room you are in, you included, set its topic." },
+    ReplyDoc { reply: "NOTICE", args: "<text>",
+               summary: "Something you should know about your last command, e.g. that the room you joined has a new name." },
+    ReplyDoc { reply: "ALIASED", args: "<alias> <room>",
+               summary: "The alias now leads to the room, in reply to ALIAS." },
+    ReplyDoc { reply: "ROOMRENAMED", args: "<old> <new>",
+               summary: "A room you are in, or asked RENAMEROOM to rename, has a new name." },
+    ReplyDoc { reply: "NOSUCHROOM", args: "<room>",
+               summary: "There is no room by that name." },
+    ReplyDoc { reply: "ROOMEXISTS", args: "<room>",
+               summary: "A room by that name has members, so the name can't be taken." },
+    ReplyDoc { reply: "JOINED", args: "<name> <connection>",
+               summary: "Somebody connected and named themselves, you included. The connection number stays the same for as long as they are connected, whatever their name." },
+    ReplyDoc { reply: "LEFT", args: "<name> <connection>",
+               summary: "Somebody with a name disconnected." },
+    ReplyDoc { reply: "RENAMED", args: "<old> <new> <connection>",
+               summary: "Somebody changed their name, you included." },
+    ReplyDoc { reply: "SERVER", args: "<text>",
+               summary: "An announcement from the server operator. Longer ones span several consecutive lines." },
+    ReplyDoc { reply: "KICKED", args: "<reason>",
+               summary: "You were disconnected by an operator or for flooding, or your address is banned." },
+    ReplyDoc { reply: "OPERED", args: "",
+               summary: "You are now an operator." },
+    ReplyDoc { reply: "BADPASSWORD", args: "",
+               summary: "That is not the operator password, or the server has none." },
+    ReplyDoc { reply: "PROTO", args: "<TEXT|JSON>",
+               summary: "The connection now speaks this protocol, starting with this reply." },
+    ReplyDoc { reply: "USAGE", args: "<verb> [<args>]",
+               summary: "How to use a command, in reply to HELP: one line per command you may use, or for the one asked about." },
+    ReplyDoc { reply: "HELPTEXT", args: "<text>",
+               summary: "What the command asked about with HELP <verb> does." },
+    ReplyDoc { reply: "ENDHELP", args: "",
+               summary: "The end of the reply to HELP." },
+    ReplyDoc { reply: "NOSUCHVERB", args: "<verb>",
+               summary: "HELP was asked about a command that doesn't exist, is disabled, or that only operators may use." },
+    ReplyDoc { reply: "ERR_DISABLED", args: "<verb>",
+               summary: "The command is disabled on this server." },
+    ReplyDoc { reply: "MOTD", args: "<text>",
+               summary: "A line of the message of the day, sent on connecting when the server has one." },
+    ReplyDoc { reply: "ENDMOTD", args: "",
+               summary: "The end of the message of the day." },
+    ReplyDoc { reply: "REHASHED", args: "<lines>",
+               summary: "The MOTD file was reread and has this many lines, in reply to REHASH." },
+    ReplyDoc { reply: "REHASHFAILED", args: "<reason>",
+               summary: "The MOTD file couldn't be read, so the message of the day is unchanged." },
+    ReplyDoc { reply: "NOTOPER", args: "",
+               summary: "Only operators may do that; see OPER." },
+    ReplyDoc { reply: "BANNED", args: "<ip>",
+               summary: "The address is now banned, in reply to BAN." },
+    ReplyDoc { reply: "MAINTENANCE", args: "<message>",
+               summary: "The server is in maintenance mode; you are disconnected." },
+    ReplyDoc { reply: "NAMEINUSE", args: "<name>",
+               summary: "Somebody else already has that name; yours is unchanged." },
+    ReplyDoc { reply: "NAMERESERVED", args: "<name>",
+               summary: "That name belongs to an account, or stands for anonymous senders; LOGIN to use an account's. Yours is unchanged." },
+    ReplyDoc { reply: "REGISTERED", args: "<name>",
+               summary: "The account was created, and you are logged in to it under its name." },
+    ReplyDoc { reply: "ACCOUNTEXISTS", args: "<name>",
+               summary: "There already is an account for that name." },
+    ReplyDoc { reply: "NOACCOUNTS", args: "",
+               summary: "The server can't create accounts: it has nowhere to keep them, or saving failed." },
+    ReplyDoc { reply: "LOGGEDIN", args: "<name>",
+               summary: "You are logged in, and have the account's name." },
+    ReplyDoc { reply: "BADLOGIN", args: "",
+               summary: "No account has that name and password." },
+    ReplyDoc { reply: "NAMED", args: "<name>",
+               summary: "The name you asked for was taken, so you were given this one instead." },
+    ReplyDoc { reply: "NONAME", args: "",
+               summary: "You tried to broadcast or send a message before setting a name." },
+    ReplyDoc { reply: "NOSUCHUSER", args: "<name>",
+               summary: "Nobody by that name is connected." },
+    ReplyDoc { reply: "ROLLED", args: "<dice> <total> <roll> ...",
+               summary: "The outcome of ROLL: the total and each die." },
+    ReplyDoc { reply: "CHOSE", args: "<option>",
+               summary: "The option CHOOSE picked." },
+    ReplyDoc { reply: "SEEN", args: "<name> <seconds-ago> <connected|spoke|disconnected>",
+               summary: "The last activity of the named client, in reply to SEEN." },
+    ReplyDoc { reply: "NOTSEEN", args: "<name>",
+               summary: "Nobody by that name has been active since the server started." },
+    ReplyDoc { reply: "PING", args: "<token>",
+               summary: "Answer with PONG and the same token. Sent after LAG, and as a keepalive when the server hasn't heard from you for a while; a keepalive left unanswered gets you disconnected." },
+    ReplyDoc { reply: "LAG", args: "<round-trip-ms> <server-ms>",
+               summary: "The PING round trip, and how long the server took to get to an event queued for you." },
+    ReplyDoc { reply: "READONLY", args: "",
+               summary: "Sent on the mirror listener for anything but QUIT, which is all it accepts." },
+    ReplyDoc { reply: "SERVERCLOSING", args: "<reason>",
+               summary: "The server is shutting down; you are disconnected after anything still on its way to you." },
+    ReplyDoc { reply: "SERVERFULL", args: "",
+               summary: "The server has as many clients as it allows; you are disconnected." },
+    ReplyDoc { reply: "IDLE", args: "<seconds>",
+               summary: "You sent nothing for this long and are disconnected." },
+    ReplyDoc { reply: "TOOLONG", args: "<max>",
+               summary: "Your line was longer than max bytes and has been discarded." },
+    ReplyDoc { reply: "THROTTLED", args: "<seconds>",
+               summary: "You are sending commands too fast; that one was ignored. Wait this long. Keep it up and you are disconnected with KICKED." },
+    ReplyDoc { reply: "ERROR", args: "<input>",
+               summary: "Your input was not understood; it is echoed back." },
+    ReplyDoc { reply: "BADENCODING", args: "",
+               summary: "Your input was not valid UTF-8, so it was ignored." }
+];
+
+/* Whether a client may use a verb: it isn't disabled, and the client is an operator if it has to be */
+fn available(v: &VerbDoc, oper: bool, config: &Config) -> bool {
+    (oper || !v.oper) && !config.disabled.iter().any(|d| d.as_slice() == v.verb)
+}
+
+/* The USAGE line HELP gives for a verb */
+fn usage(v: &VerbDoc) -> String {
+    if v.args.is_empty() { format!("USAGE {}\r\n", v.verb) } else { format!("USAGE {} {}\r\n", v.verb, v.args) }
+}
+
+pub fn dump_protocol(format: &str) -> bool {
+    match format {
+        "json" => println!("{}", json::encode(&ProtocolDoc { verbs: VERBS, replies: REPLIES })),
+        "markdown" => {
+            println!("# dikuchat protocol\n");
+            println!("All lines, in both directions, end with `\\r\\n`.\n");
+            println!("## Commands\n");
+            for v in VERBS.iter() {
+                println!("### `{} {}`\n", v.verb, v.args);
+                println!("{}\n", v.summary);
+                if !v.replies.is_empty() {
+                    let replies: Vec<String> = v.replies.iter().map(|r| format!("`{}`", r)).collect();
+                    println!("Replies: {}\n", replies.connect(", "));
+                }
+            }
+            println!("## Server messages\n");
+            for r in REPLIES.iter() {
+                println!("### `{} {}`\n", r.reply, r.args);
+                println!("{}\n", r.summary);
+            }
+        },
+        _ => return false
+    }
+    true
+}
+
+/*
+ * Time. Everything that needs the current time asks a Clock, so tests can use a MockClock and move
+ * time along by hand instead of sleeping.
+ */
+trait Clock {
+    /* Milliseconds since the epoch */
+    fn now_ms(&self) -> u64;
+}
+
+struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now_ms(&self) -> u64 {
+        let now = time::get_time();
+        now.sec as u64 * 1000 + now.nsec as u64 / 1000000
+    }
+}
+
+/* The clock a server and its handlers share */
+type SharedClock = Arc<Box<Clock + Send + Sync>>;
+
+impl Clock for SharedClock {
+    fn now_ms(&self) -> u64 {
+        (**self).now_ms()
+    }
+}
+
+/* Only moves when told to. Clones share the same time, so a test can keep one to drive the other */
+#[cfg(test)]
+#[deriving(Clone)]
+struct MockClock {
+    now: Arc<AtomicUint>
+}
+
+#[cfg(test)]
+impl MockClock {
+    fn new(start_ms: u64) -> MockClock {
+        MockClock { now: Arc::new(AtomicUint::new(start_ms as uint)) }
+    }
+
+    fn advance(&self, ms: u64) {
+        self.now.fetch_add(ms as uint, SeqCst);
+    }
+}
+
+#[cfg(test)]
+impl Clock for MockClock {
+    fn now_ms(&self) -> u64 {
+        self.now.load(SeqCst) as u64
+    }
+}
+
+/*
+ * Identifiers. An id is 64 bits: milliseconds since the epoch in the top 48 and a sequence number
+ * in the bottom 16, so ids sort by creation time. They are displayed as 13 characters of Crockford
+ * base32, which is what clients and logs get to see.
+ */
+#[deriving(PartialEq, Eq, PartialOrd, Ord, Hash, Clone)]
+pub struct Id(u64);
+
+static BASE32: &'static [u8] = b"0123456789ABCDEFGHJKMNPQRSTVWXYZ";
+
+impl fmt::Show for Id {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let Id(n) = *self;
+        let mut s = String::with_capacity(13);
+        for i in range(0u, 13).rev() {
+            s.push(BASE32[((n >> (5 * i)) & 0x1f) as uint] as char);
+        }
+        write!(f, "{}", s)
+    }
+}
+
+trait IdGenerator {
+    fn next_id(&mut self) -> Id;
+}
+
+/* The one generator every listener of a server takes ids from, so no two connections share one */
+type Ids = Arc<Mutex<Box<IdGenerator + Send>>>;
+
+/* The generator used by the server: timestamp-ordered ids */
+struct TimeIdGenerator<C> {
+    clock: C,
+    last_ms: u64,
+    seq: u64
+}
+
+impl<C: Clock> TimeIdGenerator<C> {
+    fn new(clock: C) -> TimeIdGenerator<C> {
+        TimeIdGenerator { clock: clock, last_ms: 0, seq: 0 }
+    }
+}
+
+impl<C: Clock> IdGenerator for TimeIdGenerator<C> {
+    fn next_id(&mut self) -> Id {
+        let ms = self.clock.now_ms();
+        if ms > self.last_ms {
+            self.last_ms = ms;
+            self.seq = 0;
+        } else {
+            /* Same millisecond (or the clock went back): keep counting, borrowing from the future */
+            self.seq += 1;
+            if self.seq > 0xffff {
+                self.last_ms += 1;
+                self.seq = 0;
+            }
+        }
+        Id(self.last_ms << 16 | self.seq)
+    }
+}
+
+/* Hands out 1, 2, 3, ... for tests that need predictable ids */
+#[cfg(test)]
+struct SequentialIdGenerator {
+    next: u64
+}
+
+#[cfg(test)]
+impl SequentialIdGenerator {
+    fn new() -> SequentialIdGenerator {
+        SequentialIdGenerator { next: 1 }
+    }
+}
+
+#[cfg(test)]
+impl IdGenerator for SequentialIdGenerator {
+    fn next_id(&mut self) -> Id {
+        let id = Id(self.next);
+        self.next += 1;
+        id
+    }
+}
+
+/*
+ * Things that can go wrong while serving a single client. Each of these ends that client's session
+ * at worst; none of them may take down tasks shared with other clients.
+ */
+pub enum ServerError {
+    /* Writing to the client failed (or kept blocking), so it cannot be served any more */
+    WriteFailed(IoError),
+    /* The client's entry disappeared from the registry, taking its broadcast channel with it */
+    NotRegistered(Id)
+}
+
+impl fmt::Show for ServerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            WriteFailed(ref e)    => write!(f, "write failed: {}", e),
+            NotRegistered(ref id) => write!(f, "client {} is not registered", id)
+        }
+    }
+}
+
+/*
+ * What a client's handler can be sent by the rest of the server.
+ */
+#[deriving(Clone)]
+enum Event {
+    /* A broadcast: sender name and message */
+    Message(String, String),
+    /* A private message: sender name and message */
+    Private(String, String),
+    /* A message to a room: room, sender name and message */
+    RoomMessage(String, String, String),
+    /*
+     * Presence: somebody took a name, disconnected, or changed name from the first to the second.
+     * The number is their connection's.
+     */
+    Joined(String, uint),
+    Left(String, uint),
+    Renamed(String, String, uint),
+    /* A notice from the server operator, one SERVER line per entry */
+    Announce(Vec<String>),
+    /* Disconnect, telling the client why */
+    Kick(String),
+    /* The server is shutting down for this reason: disconnect once everything queued before is written */
+    Closing(String),
+    /* Sent to ourselves for LAG: when it was queued and the round trip measured so far */
+    Probe(u64, u64),
+    /* A room the client is in was renamed from the first name to the second */
+    RoomRenamed(String, String),
+    /* A room the client is in has a new topic: room, who set it and the topic */
+    TopicChanged(String, String, String),
+    /* A room the client is in was made anonymous, or not any more */
+    AnonymousChanged(String, bool),
+    /* Maintenance mode was switched on: disconnect with this message unless allowlisted */
+    MaintenanceOn(String)
+}
+
+/*
+ * What to do with an event for a client whose outgoing queue is full: drop the event, or disconnect
+ * the client.
+ */
+#[deriving(Clone, PartialEq)]
+pub enum SlowPolicy {
+    SlowDrop,
+    SlowDisconnect
+}
+
+impl FromStr for SlowPolicy {
+    fn from_str(s: &str) -> Option<SlowPolicy> {
+        match s {
+            "drop"       => Some(SlowDrop),
+            "disconnect" => Some(SlowDisconnect),
+            _            => None
+        }
+    }
+}
+
+/*
+ * A client's outgoing event queue. It is bounded, so a client that stops reading holds up neither
+ * the broadcaster nor an ever growing backlog; when it is full the server's SlowPolicy applies.
+ */
+#[deriving(Clone)]
+struct Outbox {
+    id: Id,
+    ch: SyncSender<Event>,
+    /* Another handle on the client's socket, to disconnect it without its handler's help */
+    stream: TcpStream,
+    /* Set once the client has been disconnected for being slow */
+    slow: Arc<AtomicBool>,
+    policy: SlowPolicy
+}
+
+impl Outbox {
+    fn new(id: Id, stream: &TcpStream, config: &Config) -> (Outbox, Receiver<Event>) {
+        let (tx, rx) = sync_channel(config.outgoing_queue);
+        let outbox = Outbox {
+            id: id,
+            ch: tx,
+            stream: stream.clone(),
+            slow: Arc::new(AtomicBool::new(false)),
+            policy: config.slow_clients.clone()
+        };
+        (outbox, rx)
+    }
+
+    /* The address the client connected from */
+    fn peer(&self) -> Option<IpAddr> {
+        self.stream.clone().peer_name().ok().map(|addr| addr.ip)
+    }
+
+    /* Disconnect a client that is too far behind, logging why */
+    fn disconnect(&self) {
+        if !self.slow.swap(true, SeqCst) {
+            log!(LogWarn, client_tag(&self.id, self.peer()).as_slice(), "SLOWCLIENT {}", self.id);
+            self.close();
+        }
+    }
+
+    /* Shut the socket, which fails the handler's blocked write and has it clean up as usual */
+    fn close(&self) {
+        let mut stream = self.stream.clone();
+        stream.close_read().ok();
+        stream.close_write().ok();
+    }
+}
+
+/*
+ * A clients data structure. Shared by every handler, so it is wrapped in an RWLock.
+ *
+ * Each client is assigned an id (Id) and stores a pair: Its outgoing event queue and name. Names
+ * are unique regardless of case, and indexed by their folded form (see fold) so that checking a
+ * new one doesn't mean looking at every client.
+ *
+ * Clients also have a connection number, 1 for the first connection since the server started, 2 for
+ * the next and so on. Unlike ids these are short enough for people, so they are what clients see.
+ * Next to it is kept when the client connected.
+ */
+struct Registry {
+    by_id: HashMap<Id, (Outbox, String)>,
+    by_name: HashMap<String, Id>,
+    conns: HashMap<Id, (uint, u64)>,
+    /* Whether a taken name is given a free _1, _2, ... suffix instead of being refused */
+    suffix_names: bool
+}
+
+type Clients = Arc<RWLock<Registry>>;
+
+enum Rename {
+    /* The name the client now has, which has a suffix if the one asked for was taken */
+    NameGiven(String),
+    NameTaken,
+    Unregistered
+}
+
+impl Registry {
+    fn new() -> Registry {
+        Registry { by_id: HashMap::new(), by_name: HashMap::new(), conns: HashMap::new(), suffix_names: false }
+    }
+
+    fn insert(&mut self, id: Id, conn: uint, ch: Outbox, now_ms: u64) {
+        self.by_id.insert(id, (ch, "".to_string()));
+        self.conns.insert(id, (conn, now_ms));
+    }
+
+    /* The connection number of a client, 0 if it isn't registered */
+    fn conn(&self, id: &Id) -> uint {
+        self.conns.find(id).map_or(0, |&(conn, _)| conn)
+    }
+
+    /* When a client connected, in milliseconds since the epoch */
+    fn connected_ms(&self, id: &Id) -> u64 {
+        self.conns.find(id).map_or(0, |&(_, ms)| ms)
+    }
+
+    fn remove(&mut self, id: &Id) -> bool {
+        self.conns.pop(id);
+        match self.by_id.pop(id) {
+            Some((_, name)) => {
+                self.by_name.pop(&fold(name.as_slice()));
+                true
+            },
+            None => false
+        }
+    }
+
+    fn rename(&mut self, id: &Id, wanted: &str) -> Rename {
+        let suffix = self.suffix_names;
+        self.rename_to(id, wanted, suffix)
+    }
+
+    /* Rename to exactly this name, never a suffixed one, as logging in to an account does */
+    fn claim(&mut self, id: &Id, wanted: &str) -> Rename {
+        self.rename_to(id, wanted, false)
+    }
+
+    fn rename_to(&mut self, id: &Id, wanted: &str, suffix: bool) -> Rename {
+        let name = match self.by_name.find(&fold(wanted)) {
+            None                   => wanted.to_string(),
+            /* Our own name, perhaps in another case */
+            Some(owner) if owner == id => wanted.to_string(),
+            Some(_) if !suffix     => return NameTaken,
+            Some(_) => {
+                let mut n = 1u;
+                while self.by_name.contains_key(&fold(format!("{}_{}", wanted, n).as_slice())) { n += 1; }
+                format!("{}_{}", wanted, n)
+            }
+        };
+        match self.by_id.find_mut(id) {
+            Some(&(_, ref mut old)) => {
+                self.by_name.pop(&fold(old.as_slice()));
+                self.by_name.insert(fold(name.as_slice()), *id);
+                *old = name.clone();
+                NameGiven(name)
+            },
+            None => Unregistered
+        }
+    }
+}
+
+/*
+ * Names and rooms are looked up regardless of case, by their folded form: lowercased a character
+ * at a time, in any script, so Émile and émile are one name. The casing a client chose is kept for
+ * display.
+ */
+fn fold(name: &str) -> String {
+    name.chars().map(|c| c.to_lowercase()).collect()
+}
+
+/*
+ * Rooms, by folded name. Each room has the name it was created with, the ids of its members and,
+ * once a member sets one, a topic with the name of who set it. Messages said in a room are sent
+ * only to its members, as from ANON_NAME if an operator made the room anonymous. Rooms are created
+ * by the first JOIN and removed, topic, mode and all, when their last member leaves.
+ */
+struct Room {
+    name: String,
+    members: HashSet<Id>,
+    topic: Option<(String, String)>,
+    anonymous: bool
+}
+
+/* Who messages in anonymous rooms are from. Nobody can take it as a name */
+static ANON_NAME: &'static str = "ANON";
+
+fn on_off(on: bool) -> &'static str {
+    if on { "ON" } else { "OFF" }
+}
+
+/* Whoever holds the rooms and the clients at once takes the rooms first, as join_room does */
+type Rooms = Arc<RWLock<HashMap<String, Room>>>;
+
+/* Remove a member from a room, dropping the room if it is now empty. False if it wasn't a member */
+fn leave_room(rooms: &mut HashMap<String, Room>, room: &str, id: &Id) -> bool {
+    let key = fold(room);
+    let (was_member, empty) = match rooms.find_mut(&key) {
+        Some(room) => (room.members.remove(id), room.members.is_empty()),
+        None       => (false, false)
+    };
+    if empty {
+        rooms.pop(&key);
+    }
+    was_member
+}
+
+/*
+ * Room aliases: names that lead to another room, so a renamed room can still be found by its old
+ * name. Kept in a file when the server has one, one "<alias> <room>" line each, rewritten whenever
+ * they change.
+ */
+struct RoomAliases {
+    path: Option<Path>,
+    to_room: HashMap<String, String>
+}
+
+type Aliases = Arc<RWLock<RoomAliases>>;
+
+impl RoomAliases {
+    fn open(path: Option<Path>) -> IoResult<RoomAliases> {
+        let mut to_room = HashMap::new();
+        match path {
+            Some(ref path) if path.exists() => {
+                let text = try!(File::open(path).read_to_string());
+                for (n, line) in text.as_slice().lines().enumerate() {
+                    match split_word(line) {
+                        ("", _) => (),
+                        (alias, room) if !room.is_empty() && !room.contains_char(' ') => {
+                            to_room.insert(fold(alias), room.to_string());
+                        },
+                        _ => log!(LogWarn, "", "{}:{}: malformed alias, skipped", path.display(), n + 1)
+                    }
+                }
+            },
+            _ => ()
+        }
+        Ok(RoomAliases { path: path, to_room: to_room })
+    }
+
+    /* The room a name leads to, if it is an alias */
+    fn resolve(&self, name: &str) -> Option<String> {
+        self.to_room.find(&fold(name)).map(|room| room.clone())
+    }
+
+    /* Make alias lead to room, along with any aliases that led to alias so far. Aliasing a name to itself removes the alias */
+    fn add(&mut self, alias: &str, room: &str) -> IoResult<()> {
+        self.to_room.pop(&fold(room));
+        if fold(alias) == fold(room) {
+            return self.save();
+        }
+        for target in self.to_room.values_mut() {
+            if fold(target.as_slice()) == fold(alias) {
+                *target = room.to_string();
+            }
+        }
+        self.to_room.insert(fold(alias), room.to_string());
+        self.save()
+    }
+
+    fn save(&self) -> IoResult<()> {
+        let path = match self.path {
+            Some(ref path) => path,
+            None => return Ok(())
+        };
+        let mut aliases: Vec<(&String, &String)> = self.to_room.iter().collect();
+        aliases.sort();
+        let mut text = String::new();
+        for &(alias, room) in aliases.iter() {
+            text.push_str(format!("{} {}\n", alias, room).as_slice());
+        }
+        File::create(path).write_str(text.as_slice())
+    }
+}
+
+/* Add a member to a room, creating it if need be. The MEMBERS line that tells them who is there, its TOPIC and mode */
+fn join_room(rooms: &Rooms, clients: &Clients, room: &str, id: &Id) -> String {
+    let mut rooms = rooms.write();
+    let room = rooms.find_or_insert_with(fold(room), |_| Room { name: room.to_string(), members: HashSet::new(), topic: None, anonymous: false });
+    room.members.insert(*id);
+    let mut line = format!("MEMBERS {}", room.name);
+    let c = clients.read();
+    for member in room.members.iter() {
+        match c.by_id.find(member) {
+            Some(&(_, ref n)) if !n.is_empty() => {
+                line.push_str(" ");
+                line.push_str(n.as_slice());
+            },
+            _ => ()
+        }
+    }
+    line.push_str("\r\n");
+    match room.topic {
+        Some((ref by, ref topic)) => line.push_str(format!("TOPIC {} {} {}\r\n", room.name, by, topic).as_slice()),
+        None => ()
+    }
+    if room.anonymous {
+        line.push_str(format!("ANONYMOUS {} ON\r\n", room.name).as_slice());
+    }
+    line
+}
+
+/* The room a name leads to: the room an alias stands for, or the name itself */
+fn resolve_room(aliases: &Aliases, name: &str) -> String {
+    aliases.read().resolve(name).unwrap_or_else(|| name.to_string())
+}
+
+fn save_alias(aliases: &Aliases, alias: &str, room: &str) {
+    match aliases.write().add(alias, room) {
+        Ok(()) => (),
+        Err(e) => log!(LogError, "", "Saving room aliases: {}", e)
+    }
+}
+
+/* The rooms a client is in, sorted */
+fn rooms_of(rooms: &HashMap<String, Room>, id: &Id) -> Vec<String> {
+    let mut joined: Vec<String> = rooms.values().filter(|r| r.members.contains(id)).map(|r| r.name.clone()).collect();
+    joined.sort();
+    joined
+}
+
+/* Join those of these rooms the client isn't in yet, as JOIN would. The MEMBERS lines for them */
+fn auto_join(rooms: &Rooms, clients: &Clients, aliases: &Aliases, wanted: &[String], id: &Id) -> String {
+    let mut lines = String::new();
+    for room in wanted.iter() {
+        let room = resolve_room(aliases, room.as_slice());
+        let member = rooms.read().find(&fold(room.as_slice())).map_or(false, |room| room.members.contains(id));
+        if !member {
+            lines.push_str(join_room(rooms, clients, room.as_slice(), id).as_slice());
+        }
+    }
+    lines
+}
+
+/*
+ * Read-only watchers: connections to the mirror listener, which get a copy of every broadcast (no
+ * room), and SSE streams, which get a copy of everything said in their room (kept folded, see
+ * fold). Nothing else reaches them.
+ */
+type Mirrors = Arc<RWLock<HashMap<Id, (Outbox, Option<String>)>>>;
+
+/* Send an event to every connected client */
+fn send_all(clients: &Clients, stats: &Stats, ev: Event) {
+    for &(ref client, _) in clients.read().by_id.values() {
+        stats.deliver(client, ev.clone());
+    }
+}
+
+/*
+ * Tell a client or watcher that the server is closing. Unlike deliver(), this waits for room in a
+ * full queue rather than dropping the event, so the last thing a slow client gets is still the
+ * reason it is being disconnected. The wait happens in a task of its own, and ends when the
+ * handler does.
+ */
+fn send_closing(stats: Arc<Stats>, to: &Outbox, reason: &str) {
+    let (ch, reason) = (to.ch.clone(), reason.to_string());
+    stats.queued_events.fetch_add(1, SeqCst);
+    spawn(proc() {
+        if ch.send_opt(Closing(reason)).is_err() {
+            stats.queued_events.fetch_sub(1, SeqCst);
+        }
+    });
+}
+
+/* The outgoing queue of the client with this name, if anyone has it */
+fn find_client(clients: &Clients, name: &str) -> Option<Outbox> {
+    let c = clients.read();
+    c.by_name.find(&fold(name)).and_then(|id| c.by_id.find(id)).map(|&(ref ch, _)| ch.clone())
+}
+
+/*
+ * Last activity per name, for SEEN. Kept in memory, so it covers the time since the server started.
+ */
+#[deriving(Show)]
+enum Activity {
+    Connected,
+    Spoke,
+    Disconnected
+}
+
+type LastSeen = Arc<RWLock<HashMap<String, (u64, Activity)>>>;
+
+fn saw(seen: &LastSeen, now_ms: u64, name: &str, what: Activity) {
+    if !name.is_empty() {
+        seen.write().insert(fold(name), (now_ms, what));
+    }
+}
+
+/*
+ * The most recent broadcasts, for HISTORY and to catch up new clients: when each was sent (in
+ * milliseconds), who sent it, and what it said. Holds up to the configured history_size.
+ */
+type Backlog = Arc<RWLock<RingBuf<(u64, String, String)>>>;
+
+fn remember(backlog: &Backlog, size: uint, now_ms: u64, name: &str, text: &str) {
+    if size == 0 { return }
+    let mut backlog = backlog.write();
+    if backlog.len() >= size {
+        backlog.pop_front();
+    }
+    backlog.push_back((now_ms, name.to_string(), text.to_string()));
+}
+
+/*
+ * The message of the day, a line each, sent to clients as they connect. Read from the configured
+ * motd_file at startup and again on REHASH; without a file there is none.
+ */
+type Motd = Arc<RWLock<Vec<String>>>;
+
+fn read_motd(path: &Option<Path>) -> IoResult<Vec<String>> {
+    match *path {
+        Some(ref path) => {
+            let text = try!(File::open(path).read_to_string());
+            Ok(text.as_slice().lines().map(|line| line.trim_right().to_string()).collect())
+        },
+        None => Ok(Vec::new())
+    }
+}
+
+/* The MOTD lines and ENDMOTD, or nothing if there is no message of the day */
+fn motd_lines(motd: &Motd) -> String {
+    let motd = motd.read();
+    if motd.is_empty() { return String::new() }
+    let mut lines = String::new();
+    for line in motd.iter() {
+        lines.push_str(format!("MOTD {}\r\n", line).as_slice());
+    }
+    lines.push_str("ENDMOTD\r\n");
+    lines
+}
+
+/* The REPLAY lines for the last count broadcasts, oldest first */
+fn history_lines(backlog: &Backlog, count: uint) -> String {
+    let backlog = backlog.read();
+    let mut lines = String::new();
+    for &(ms, ref name, ref text) in backlog.iter().skip(backlog.len() - std::cmp::min(count, backlog.len())) {
+        lines.push_str(format!("REPLAY {} {} {}\r\n", ms / 1000, name, text).as_slice());
+    }
+    lines
+}
+
+/*
+ * Maintenance mode. While it is on, only connections from allowlisted addresses are served; everyone
+ * else is sent MAINTENANCE with the operator's message and disconnected.
+ */
+struct Maintenance {
+    message: Option<String>,
+    allow: Vec<IpAddr>
+}
+
+impl Maintenance {
+    /* The message to turn this peer away with, if it is not welcome right now */
+    fn refuse(&self, peer: Option<IpAddr>) -> Option<String> {
+        match (&self.message, peer) {
+            (&Some(_), Some(ip)) if self.allow.contains(&ip) => None,
+            (&Some(ref message), _) => Some(message.clone()),
+            (&None, _) => None
+        }
+    }
+}
+
+static MAINTENANCE_MESSAGE: &'static str = "The server is down for maintenance, please come back later";
+
+/*
+ * Addresses banned by an operator. Connections from them are sent KICKED and closed as soon as they
+ * are accepted. Kept in memory, so bans last until the server restarts or the console lifts them.
+ */
+type Bans = Arc<RWLock<Vec<IpAddr>>>;
+
+static KICK_REASON: &'static str = "Kicked by operator";
+static SHUTDOWN_REASON: &'static str = "Server shutting down";
+static BAN_REASON: &'static str = "Banned by operator";
+
+/*
+ * Accounts. A registered name is reserved for whoever knows its password. Accounts are kept in a
+ * file, one "<name> <salt> <hash> [<room>,...]" line each, read back when the server starts. The file
+ * is written whole to <file>.tmp and renamed over the old one whenever an account is created or
+ * its rooms change, so it never holds more than a line per account and a crash leaves one or the
+ * other. (Should a name have several lines anyway, the last one wins.) The hash
+ * is SHA-256 applied PASSWORD_ROUNDS times over the salt and password, so the file isn't worth much
+ * to whoever gets hold of it.
+ */
+static PASSWORD_ROUNDS: uint = 10000;
+
+struct Account {
+    /* As it was registered, rather than folded */
+    name: String,
+    /* Both hex */
+    salt: String,
+    hash: String,
+    /* The rooms the account was last in, joined again when it logs in */
+    rooms: Vec<String>
+}
+
+struct Accounts {
+    /* None when the server has nowhere to keep accounts, and so has none */
+    path: Option<Path>,
+    by_name: HashMap<String, Account>
+}
+
+type AccountStore = Arc<RWLock<Accounts>>;
+
+impl Accounts {
+    fn open(path: Option<Path>) -> IoResult<Accounts> {
+        let mut by_name = HashMap::new();
+        match path {
+            Some(ref path) if path.exists() => {
+                let text = try!(File::open(path).read_to_string());
+                for (n, line) in text.as_slice().lines().enumerate() {
+                    let fields: Vec<&str> = line.words().collect();
+                    let (name, salt, hash, rooms) = match fields.as_slice() {
+                        [name, salt, hash] => (name, salt, hash, ""),
+                        [name, salt, hash, rooms] => (name, salt, hash, rooms),
+                        [] => continue,
+                        _  => {
+                            log!(LogWarn, "", "{}:{}: malformed account, skipped", path.display(), n + 1);
+                            continue;
+                        }
+                    };
+                    by_name.insert(fold(name), Account {
+                        name: name.to_string(),
+                        salt: salt.to_string(),
+                        hash: hash.to_string(),
+                        rooms: rooms.split(',').filter(|r| !r.is_empty()).map(|r| r.to_string()).collect()
+                    });
+                }
+            },
+            _ => ()
+        }
+        Ok(Accounts { path: path, by_name: by_name })
+    }
+
+    fn is_registered(&self, name: &str) -> bool {
+        self.by_name.contains_key(&fold(name))
+    }
+
+    fn check(&self, name: &str, password: &str) -> bool {
+        match self.by_name.find(&fold(name)) {
+            Some(account) => hash_password(account.salt.as_slice(), password) == account.hash,
+            None => false
+        }
+    }
+
+    /* The rooms an account is to join when it logs in */
+    fn rooms(&self, name: &str) -> Vec<String> {
+        self.by_name.find(&fold(name)).map_or(Vec::new(), |account| account.rooms.clone())
+    }
+
+    /* Create an account, saving it before it counts */
+    fn register(&mut self, name: &str, password: &str) -> IoResult<()> {
+        let salt: Vec<u8> = range(0u, 16).map(|_| task_rng().gen::<u8>()).collect();
+        let salt = salt.as_slice().to_hex();
+        let hash = hash_password(salt.as_slice(), password);
+        self.by_name.insert(fold(name), Account { name: name.to_string(), salt: salt, hash: hash, rooms: Vec::new() });
+        let saved = self.save();
+        if saved.is_err() {
+            self.by_name.pop(&fold(name));
+        }
+        saved
+    }
+
+    /* Remember the rooms an account is in now, saving them before they count */
+    fn set_rooms(&mut self, name: &str, rooms: Vec<String>) -> IoResult<()> {
+        let key = fold(name);
+        let old = match self.by_name.find_mut(&key) {
+            Some(account) if account.rooms != rooms => std::mem::replace(&mut account.rooms, rooms),
+            _ => return Ok(())
+        };
+        let saved = self.save();
+        if saved.is_err() {
+            self.by_name.find_mut(&key).unwrap().rooms = old;
+        }
+        saved
+    }
+
+    fn save(&self) -> IoResult<()> {
+        let path = match self.path {
+            Some(ref path) => path,
+            None => return Err(standard_error(InvalidInput))
+        };
+        let mut accounts: Vec<&Account> = self.by_name.values().collect();
+        accounts.sort_by(|a, b| fold(a.name.as_slice()).cmp(&fold(b.name.as_slice())));
+        let mut text = String::new();
+        for account in accounts.iter() {
+            if account.rooms.is_empty() {
+                text.push_str(format!("{} {} {}\n", account.name, account.salt, account.hash).as_slice());
+            } else {
+                text.push_str(format!("{} {} {} {}\n", account.name, account.salt, account.hash, account.rooms.connect(",")).as_slice());
+            }
+        }
+        let mut tmp = path.as_vec().to_vec();
+        tmp.push_all(b".tmp");
+        let tmp = Path::new(tmp);
+        try!(File::create(&tmp).write_str(text.as_slice()));
+        std::io::fs::rename(&tmp, path)
+    }
+}
+
+fn hash_password(salt: &str, password: &str) -> String {
+    let mut sha = Sha256::new();
+    let mut digest = Vec::from_elem(sha.output_bytes(), 0u8);
+    for _ in range(0, PASSWORD_ROUNDS) {
+        sha.reset();
+        sha.input(digest.as_slice());
+        sha.input_str(salt);
+        sha.input_str(password);
+        sha.result(digest.as_mut_slice());
+    }
+    digest.as_slice().to_hex()
+}
+
+/*
+ * Server-wide counters, shown by the console's stats command and served as metrics on the HTTP
+ * listener's /metrics.
+ */
+struct Stats {
+    clock: SharedClock,
+    started_ms: u64,
+    connections: AtomicUint,
+    broadcasts: AtomicUint,
+    /* Lines answered with ERROR because they aren't a command process_input understands */
+    parse_errors: AtomicUint,
+    /* How many times each verb has been handled */
+    commands: Mutex<HashMap<&'static str, uint>>,
+    /* Live registry entries and handler tasks, which should always agree */
+    registered: AtomicUint,
+    handlers: AtomicUint,
+    /* Events sent to handlers and not yet received by them */
+    queued_events: AtomicUint,
+    /* Events not sent because the client's outgoing queue was full */
+    dropped_events: AtomicUint,
+    /* The last LATENCY_SAMPLES handler times per verb, in microseconds */
+    latency: Mutex<HashMap<&'static str, RingBuf<u64>>>
+}
+
+impl Stats {
+    fn new(clock: SharedClock) -> Stats {
+        Stats {
+            started_ms: clock.now_ms(),
+            clock: clock,
+            connections: AtomicUint::new(0),
+            broadcasts: AtomicUint::new(0),
+            parse_errors: AtomicUint::new(0),
+            commands: Mutex::new(HashMap::new()),
+            registered: AtomicUint::new(0),
+            handlers: AtomicUint::new(0),
+            queued_events: AtomicUint::new(0),
+            dropped_events: AtomicUint::new(0),
+            latency: Mutex::new(HashMap::new())
+        }
+    }
+
+    /*
+     * Send an event to a handler, counting it as queued until the handler receives it. Never blocks:
+     * if the client is too far behind, the event is dropped and the client may be disconnected.
+     */
+    fn deliver(&self, to: &Outbox, ev: Event) {
+        match to.ch.try_send(ev) {
+            Ok(()) => { self.queued_events.fetch_add(1, SeqCst); },
+            Err(Full(_)) => {
+                self.dropped_events.fetch_add(1, SeqCst);
+                if to.policy == SlowDisconnect {
+                    to.disconnect();
+                }
+            },
+            /* The handler is gone, and takes the client out of the registry on its way */
+            Err(RecvDisconnected(_)) => ()
+        }
+    }
+
+    fn uptime_s(&self) -> u64 {
+        (self.clock.now_ms() - self.started_ms) / 1000
+    }
+
+    fn record_latency(&self, verb: &'static str, us: u64) {
+        if verb.is_empty() { return }
+        *self.commands.lock().find_or_insert(verb, 0) += 1;
+        let mut latency = self.latency.lock();
+        let samples = latency.find_or_insert_with(verb, |_| RingBuf::new());
+        if samples.len() == LATENCY_SAMPLES {
+            samples.pop_front();
+        }
+        samples.push_back(us);
+    }
+
+    /* The 50th, 95th and 99th percentile handler time of each verb seen so far, sorted by verb */
+    fn latency_percentiles(&self) -> Vec<(&'static str, uint, [u64, ..3])> {
+        let latency = self.latency.lock();
+        let mut out: Vec<(&'static str, uint, [u64, ..3])> = latency.iter().map(|(&verb, samples)| {
+            let mut sorted: Vec<u64> = samples.iter().map(|&us| us).collect();
+            sorted.sort();
+            let at = |p: uint| sorted[(sorted.len() - 1) * p / 100];
+            (verb, sorted.len(), [at(50), at(95), at(99)])
+        }).collect();
+        out.sort_by(|&(a, _, _), &(b, _, _)| a.cmp(&b));
+        out
+    }
+
+    /* The counters in the Prometheus text format */
+    fn metrics(&self) -> String {
+        fn metric(out: &mut String, name: &str, kind: &str, help: &str, value: u64) {
+            out.push_str(format!("# HELP dikuchat_{} {}\n# TYPE dikuchat_{} {}\ndikuchat_{} {}\n",
+                                 name, help, name, kind, name, value).as_slice());
+        }
+        let mut out = String::new();
+        metric(&mut out, "uptime_seconds", "gauge", "Seconds since the server started.", self.uptime_s());
+        metric(&mut out, "clients", "gauge", "Connected clients.", self.registered.load(SeqCst) as u64);
+        metric(&mut out, "connections_total", "counter", "Connections accepted.", self.connections.load(SeqCst) as u64);
+        metric(&mut out, "broadcasts_total", "counter", "Messages broadcast.", self.broadcasts.load(SeqCst) as u64);
+        metric(&mut out, "received_bytes_total", "counter", "Bytes read from clients.", BYTES_IN.load(SeqCst) as u64);
+        metric(&mut out, "sent_bytes_total", "counter", "Bytes written to clients.", BYTES_OUT.load(SeqCst) as u64);
+        metric(&mut out, "parse_errors_total", "counter", "Lines that weren't a command.", self.parse_errors.load(SeqCst) as u64);
+        metric(&mut out, "dropped_events_total", "counter", "Events dropped for slow clients.", self.dropped_events.load(SeqCst) as u64);
+        out.push_str("# HELP dikuchat_commands_total Commands handled, by verb.\n# TYPE dikuchat_commands_total counter\n");
+        let commands = self.commands.lock();
+        let mut verbs: Vec<&&'static str> = commands.keys().collect();
+        verbs.sort();
+        for verb in verbs.into_iter() {
+            out.push_str(format!("dikuchat_commands_total{{verb=\"{}\"}} {}\n", verb, commands[*verb]).as_slice());
+        }
+        out.push_str("# HELP dikuchat_command_latency_microseconds Handler time of recent commands, by verb and quantile.\n\
+                      # TYPE dikuchat_command_latency_microseconds gauge\n");
+        for &(verb, _, [p50, p95, p99]) in self.latency_percentiles().iter() {
+            for &(quantile, us) in [("0.5", p50), ("0.95", p95), ("0.99", p99)].iter() {
+                out.push_str(format!("dikuchat_command_latency_microseconds{{verb=\"{}\",quantile=\"{}\"}} {}\n",
+                                     verb, quantile, us).as_slice());
+            }
+        }
+        out
+    }
+}
+
+/*
+ * Input processing. A line is a verb, matched regardless of case, and its arguments, separated by
+ * spaces or tabs. An argument starting with ':' is the last one and runs to the end of the line,
+ * spaces and all, IRC style; commands whose last argument is free text take the rest of the line
+ * anyway, so the ':' is only needed for text that starts with one. Lines are UTF-8, checked by the
+ * reader before they get here; verbs are ASCII, their arguments needn't be.
+ */
+static SPACE: &'static [char] = &[' ', '\t'];
+
+struct Args<'a> {
+    line: &'a str,
+    /* Where each argument starts in the line, and the argument */
+    words: Vec<(uint, &'a str)>,
+    /* Whether the last argument was introduced by ':' */
+    trailing: bool
+}
+
+impl<'a> Args<'a> {
+    fn len(&self) -> uint {
+        self.words.len()
+    }
+
+    /* Argument i, if it is a single word (a ':' argument may be one too) */
+    fn word(&self, i: uint) -> Option<&'a str> {
+        match self.words.as_slice().get(i) {
+            Some(&(_, word)) if !word.is_empty() && !word.contains_char(' ') => Some(word),
+            _ => None
+        }
+    }
+
+    /* The text from argument i to the end of the line, if there is any */
+    fn text(&self, i: uint) -> Option<&'a str> {
+        let text = match self.words.as_slice().get(i) {
+            Some(&(_, word)) if self.trailing && i + 1 == self.words.len() => word,
+            Some(&(at, _)) => self.line.slice_from(at).trim_right_chars(SPACE),
+            None => return None
+        };
+        if text.is_empty() { None } else { Some(text) }
+    }
+
+    /* The only argument, if there is exactly one and it is a single word */
+    fn only(&self) -> Option<String> {
+        if self.len() == 1 { self.word(0).map(|word| word.to_string()) } else { None }
+    }
+}
+
+/*
+ * Whether text holds a control character other than tab. A \r or \n in a name or a message would
+ * end the line it is echoed in, letting one client forge lines in everyone else's stream.
+ */
+fn has_control(text: &str) -> bool {
+    text.chars().any(|c| c != '\t' && c.is_control())
+}
+
+/* Text as it can safely be echoed back, with its control characters shown as '?' */
+fn printable(text: &str) -> String {
+    text.chars().map(|c| if c != '\t' && c.is_control() { '?' } else { c }).collect()
+}
+
+/* Split a line into its verb, in uppercase, and its arguments */
+fn tokenize<'a>(line: &'a str) -> (String, Args<'a>) {
+    let (mut words, mut trailing) = (Vec::new(), false);
+    let (mut rest, mut at) = (line, 0u);
+    loop {
+        let skipped = rest.len() - rest.trim_left_chars(SPACE).len();
+        rest = rest.slice_from(skipped);
+        at += skipped;
+        if rest.is_empty() { break }
+        /* The verb can't start with ':', only arguments */
+        if !words.is_empty() && rest.starts_with(":") {
+            words.push((at + 1, rest.slice_from(1)));
+            trailing = true;
+            break;
+        }
+        let len = rest.find(SPACE).unwrap_or(rest.len());
+        words.push((at, rest.slice_to(len)));
+        rest = rest.slice_from(len);
+        at += len;
+    }
+    let verb = if words.is_empty() { String::new() } else { words.remove(0).unwrap().val1().to_ascii_upper() };
+    (verb, Args { line: line, words: words, trailing: trailing })
+}
+
+fn process_input(inp: &str) -> Option<Method> {
+    if has_control(inp) {
+        return None;
+    }
+    let (verb, args) = tokenize(inp);
+    /* Only documented verbs are understood, so HELP and --dump-protocol can't leave one out */
+    if !VERBS.iter().any(|v| v.verb == verb.as_slice()) {
+        return None;
+    }
+    match verb.as_slice() {
+        "QUIT" => Some(Quit),
+        "WHO"  => if args.len() == 0 { Some(Who(None)) } else { args.only().map(|p| Who(Some(p))) },
+        "WHOX" => Some(Whox),
+        "NAME" => args.only().and_then(|name| if name.as_slice().char_len() > MAX_NAME { None } else { Some(Name(name)) }),
+        "BROADCAST" => Some(Broadcast(args.text(0).unwrap_or("").to_string())),
+        "MSG"    => match (args.word(0), args.text(1)) {
+            (Some(target), Some(text)) => Some(Msg(target.to_string(), text.to_string())),
+            _ => None
+        },
+        "JOIN"   => args.only().map(Join),
+        "PART"   => args.only().map(Part),
+        "LIST"   => Some(List),
+        "SAY"    => match (args.word(0), args.text(1)) {
+            (Some(room), Some(text)) => Some(Say(room.to_string(), text.to_string())),
+            _ => None
+        },
+        "TOPIC"  => args.word(0).map(|room| Topic(room.to_string(), args.text(1).map(|text| text.to_string()))),
+        "ROLL"   => args.only().and_then(|dice| parse_dice(dice.as_slice())).map(|(n, sides)| Roll(n, sides)),
+        "CHOOSE" => {
+            let options: Vec<String> = args.text(0).unwrap_or("")
+                                                   .split('|')
+                                                   .map(|o| o.trim())
+                                                   .filter(|o| !o.is_empty())
+                                                   .map(|o| o.to_string())
+                                                   .collect();
+            if options.is_empty() { None } else { Some(Choose(options)) }
+        },
+        "SEEN"   => args.only().map(Seen),
+        "LAG"    => Some(Lag),
+        "PONG"   => args.only().map(Pong),
+        "OPER"   => args.text(0).map(|password| Oper(password.to_string())),
+        "KICK"   => args.word(0).map(|target| KickUser(target.to_string(), args.text(1).unwrap_or(KICK_REASON).to_string())),
+        "BAN"    => args.only().map(Ban),
+        "REGISTER" => match (args.word(0), args.text(1)) {
+            (Some(name), Some(password)) if name.char_len() <= MAX_NAME => Some(Register(name.to_string(), password.to_string())),
+            _ => None
+        },
+        "LOGIN"  => match (args.word(0), args.text(1)) {
+            (Some(name), Some(password)) => Some(Login(name.to_string(), password.to_string())),
+            _ => None
+        },
+        "HISTORY" => match args.len() {
+            0 => Some(History(None)),
+            _ => args.only().and_then(|count| from_str(count.as_slice())).map(|n| History(Some(n)))
+        },
+        "ALIAS"  => match (args.len(), args.word(0), args.word(1)) {
+            (2, Some(alias), Some(room)) => Some(AliasRoom(alias.to_string(), room.to_string())),
+            _ => None
+        },
+        "RENAMEROOM" => match (args.len(), args.word(0), args.word(1)) {
+            (2, Some(room), Some(new)) => Some(RenameRoom(room.to_string(), new.to_string())),
+            _ => None
+        },
+        "REHASH" => Some(Rehash),
+        "ANONYMOUS" => match (args.len(), args.word(0), args.word(1).map(|mode| mode.to_ascii_upper())) {
+            (2, Some(room), Some(ref mode)) if mode.as_slice() == "ON" || mode.as_slice() == "OFF" => {
+                Some(Anonymous(room.to_string(), mode.as_slice() == "ON"))
+            },
+            _ => None
+        },
+        "PROTO"  => match args.only().map(|mode| mode.into_ascii_upper()) {
+            Some(ref mode) if mode.as_slice() == "TEXT" || mode.as_slice() == "JSON" => Some(Proto(mode.clone())),
+            _ => None
+        },
+        "HELP"   => if args.len() == 0 { Some(Help(None)) } else { args.only().map(|v| Help(Some(v.into_ascii_upper()))) },
+        _      => None
+    }
+}
+
+/*
+ * Utility commands. Small conveniences handled by the same parser and handler as the core verbs.
+ */
+static MAX_DICE: uint = 100;
+static MAX_SIDES: uint = 1000;
+
+/* "NdM", e.g. "2d6". A missing N means one die */
+fn parse_dice(spec: &str) -> Option<(uint, uint)> {
+    let spec = spec.trim();
+    let d = match spec.find('d') {
+        Some(d) => d,
+        None    => return None
+    };
+    let n = if d == 0 { Some(1) } else { from_str::<uint>(spec.slice_to(d)) };
+    match (n, from_str::<uint>(spec.slice_from(d + 1))) {
+        (Some(n), Some(sides)) if n >= 1 && n <= MAX_DICE && sides >= 2 && sides <= MAX_SIDES => Some((n, sides)),
+        _ => None
+    }
+}
+
+fn roll(n: uint, sides: uint) -> String {
+    let mut rng = task_rng();
+    let rolls: Vec<uint> = range(0, n).map(|_| rng.gen_range(1, sides + 1)).collect();
+    let total = rolls.iter().fold(0, |a, &b| a + b);
+    let rolls: Vec<String> = rolls.iter().map(|r| r.to_string()).collect();
+    format!("ROLLED {}d{} {} {}\r\n", n, sides, total, rolls.connect(" "))
+}
+
+/*
+ * Line framing. Bytes read from a client are collected until a \r\n, so a command can arrive split
+ * over several reads and several commands can arrive in one. A line longer than the maximum is
+ * reported once as TooLong, and the rest of it, up to and including its \r\n, is skipped.
+ */
+enum Line {
+    /* A whole line, without the \r\n */
+    Complete(Vec<u8>),
+    TooLong
+}
+
+struct LineReader {
+    buf: Vec<u8>,
+    max: uint,
+    skipping: bool
+}
+
+impl LineReader {
+    fn new(max: uint) -> LineReader {
+        LineReader { buf: Vec::new(), max: max, skipping: false }
+    }
+
+    /* Take the bytes of one read, returning the lines they complete */
+    fn feed(&mut self, chunk: &[u8]) -> Vec<Line> {
+        let mut lines = Vec::new();
+        for &b in chunk.iter() {
+            if b == b'\n' && self.buf.last() == Some(&b'\r') {
+                self.buf.pop();
+                if !self.skipping {
+                    lines.push(Complete(self.buf.clone()));
+                }
+                self.buf.clear();
+                self.skipping = false;
+            } else {
+                if self.skipping {
+                    /* Only the last byte is kept, to spot a \r\n split over two reads */
+                    self.buf.clear();
+                }
+                self.buf.push(b);
+                /* One more than max leaves room for the \r */
+                if !self.skipping && self.buf.len() > self.max + 1 {
+                    lines.push(TooLong);
+                    self.skipping = true;
+                    self.buf.clear();
+                    self.buf.push(b);
+                }
+            }
+        }
+        lines
+    }
+}
+
+/*
+ * Session recording, for reproducing parser bugs reported by users. Only enabled by starting the
+ * server with --record <dir>, in which case every read from every client is appended to
+ * <dir>/<id>.rec as a big-endian u32 length followed by the bytes read. Read boundaries are kept, so
+ * --replay feeds the parser exactly what it saw, except that the passwords given to OPER, REGISTER
+ * and LOGIN are overwritten with '*' first. So that a password split over several reads is caught
+ * too, reads are held back until the lines they are part of are complete.
+ */
+fn open_recording(dir: &Path, id: &Id) -> Option<File> {
+    let path = dir.join(format!("{}.rec", id));
+    match File::create(&path) {
+        Ok(f)  => Some(f),
+        Err(e) => {
+            log!(LogError, "", "Cannot record to {}: {}", path.display(), e);
+            None
+        }
+    }
+}
+
+struct Recording {
+    file: Option<File>,
+    /* Reads not yet written, because the last of them ends part way into a line */
+    pending: Vec<Vec<u8>>,
+    /* Past this many bytes a line is too long to be served, and is written as it comes */
+    max_line: uint
+}
+
+impl Recording {
+    fn new(file: Option<File>, max_line: uint) -> Recording {
+        Recording { file: file, pending: Vec::new(), max_line: max_line }
+    }
+
+    /* Take one read, writing it and those before it once the lines in them are complete */
+    fn record(&mut self, chunk: &[u8]) {
+        if self.file.is_none() { return }
+        self.pending.push(chunk.to_vec());
+        let mut data: Vec<u8> = Vec::new();
+        for read in self.pending.iter() {
+            data.push_all(read.as_slice());
+        }
+        let complete = range(1, data.len()).rev().find(|&i| data[i - 1] == b'\r' && data[i] == b'\n').map(|i| i + 1);
+        let end = match complete {
+            Some(end) => end,
+            None if data.len() > self.max_line + 2 => data.len(),
+            None => return
+        };
+        let mut start = 0u;
+        for i in range(0, end) {
+            if i + 1 == end || (data[i] == b'\n' && i > 0 && data[i - 1] == b'\r') {
+                redact(&mut data, start, i + 1);
+                start = i + 1;
+            }
+        }
+        /* Write the reads that end within the complete lines. One that runs past them waits, redacted so far */
+        let (mut at, mut reads) = (0u, Vec::new());
+        for read in self.pending.iter() {
+            reads.push(data.slice(at, at + read.len()).to_vec());
+            at += read.len();
+        }
+        let mut written = 0u;
+        at = 0;
+        for read in reads.iter() {
+            if at + read.len() > end { break }
+            self.write(read.as_slice());
+            at += read.len();
+            written += 1;
+        }
+        self.pending = reads.into_iter().skip(written).collect();
+    }
+
+    /* Write whatever is still held back, as the connection is over */
+    fn finish(&mut self) {
+        let pending = std::mem::replace(&mut self.pending, Vec::new());
+        for read in pending.iter() {
+            self.write(read.as_slice());
+        }
+    }
+
+    fn write(&mut self, read: &[u8]) {
+        let failed = match self.file {
+            Some(ref mut f) => f.write_be_u32(read.len() as u32).and_then(|()| f.write(read)).is_err(),
+            None            => false
+        };
+        if failed {
+            log!(LogWarn, "", "Recording failed, disabling it for this connection.");
+            self.file = None;
+        }
+    }
+}
+
+/*
+ * Overwrite the password in the line data[start..end] (which may end in \r\n) with '*', keeping its
+ * length. A JSON line is overwritten whole, since its members may come in any order.
+ */
+fn redact(data: &mut Vec<u8>, start: uint, end: uint) {
+    let from = {
+        let line = data.slice(start, end);
+        let line = if line.ends_with(b"\r\n") { line.slice_to(line.len() - 2) } else { line };
+        let secret = |verb: &str| verb == "OPER" || verb == "REGISTER" || verb == "LOGIN";
+        match std::str::from_utf8(line) {
+            Some(text) if text.trim_left_chars(SPACE).starts_with("{") => match json_to_line(line) {
+                Some(ref converted) if std::str::from_utf8(converted.as_slice()).map_or(false, |l| secret(tokenize(l).val0().as_slice())) => {
+                    Some((0, line.len()))
+                },
+                _ => None
+            },
+            Some(text) => {
+                let (verb, args) = tokenize(text);
+                let skip = match verb.as_slice() {
+                    "OPER" => 0,
+                    "REGISTER" | "LOGIN" => 1,
+                    _ => return
+                };
+                args.words.as_slice().get(skip).map(|&(at, _)| (at, line.len()))
+            },
+            None => None
+        }
+    };
+    match from {
+        Some((from, to)) => for i in range(start + from, start + to) {
+            *data.get_mut(i) = b'*';
+        },
+        None => ()
+    }
+}
+
+pub fn replay(path: &Path) -> IoResult<()> {
+    let mut f = try!(File::open(path));
+    let mut lines = LineReader::new(MAX_LINE);
+    loop {
+        let len = match f.read_be_u32() {
+            Ok(len) => len as uint,
+            Err(ref e) if e.kind == EndOfFile => break,
+            Err(e) => {
+                println!("Truncated recording: {}", e);
+                break;
+            }
+        };
+        match f.read_exact(len) {
+            Ok(chunk) => {
+                println!("{} bytes", len);
+                for line in lines.feed(chunk.as_slice()).into_iter() {
+                    match line {
+                        Complete(line) => match String::from_utf8(line) {
+                            Ok(line) => println!("  => {}", process_input(line.as_slice())),
+                            Err(_)   => println!("  => not UTF-8")
+                        },
+                        TooLong        => println!("  => too long")
+                    }
+                }
+            },
+            Err(e)    => {
+                println!("Truncated recording: {}", e);
+                break;
+            }
+        }
+    }
+    Ok(())
+}
+
+/*
+ * Write all of buf to a client. Writes that come up short are resumed where they stopped, and
+ * writes that would block or time out are retried a few times after a short pause. Any other
+ * error (or running out of retries) is returned, and the caller should treat the client as gone.
+ */
+fn write_all<W: Writer>(w: &mut W, mut buf: &[u8]) -> IoResult<()> {
+    let mut retries = 0u;
+    loop {
+        match w.write(buf) {
+            Ok(()) => return Ok(()),
+            Err(IoError { kind: ShortWrite(n), .. }) => buf = buf.slice_from(n),
+            Err(ref e) if (e.kind == ResourceUnavailable || e.kind == TimedOut) && retries < WRITE_RETRIES => {
+                retries += 1;
+                timer::sleep(Duration::milliseconds(WRITE_RETRY_MS));
+            },
+            Err(e) => return Err(e)
+        }
+    }
+}
+
+/*
+ * Protocol tracing, for teaching the protocol and debugging clients against a local server. Each
+ * line is printed as "<id> <- <line>" for input and "<id> -> <line>" for output.
+ */
+fn trace(id: &Id, dir: &str, bytes: &[u8]) {
+    if TRACE_PROTOCOL.load(Relaxed) {
+        for line in String::from_utf8_lossy(bytes).as_slice().lines_any() {
+            println!("{} {} {}", id, dir, line);
+        }
+    }
+}
+
+fn reply<W: Writer>(w: &mut W, id: &Id, buf: &[u8]) -> Result<(), ServerError> {
+    trace(id, "->", buf);
+    BYTES_OUT.fetch_add(buf.len(), Relaxed);
+    write_all(w, buf).map_err(WriteFailed)
+}
+
+/* Have a client's handler reply with these lines, for its reader. Fails once the handler is gone */
+fn relay(out: &SyncSender<Outgoing>, lines: String) -> Result<(), ServerError> {
+    out.send_opt(OutLines(lines)).map_err(|_| WriteFailed(standard_error(BrokenPipe)))
+}
+
+/*
+ * Flood protection, a token bucket per client. Commands cost tokens, which come back at rate per
+ * second up to burst. A client that runs out is throttled; strikes counts how often that happened
+ * since the bucket was last full, so a client that keeps at it can be told apart from a burst.
+ */
+struct TokenBucket {
+    rate: f64,
+    burst: f64,
+    tokens: f64,
+    last_ms: u64,
+    strikes: uint
+}
+
+impl TokenBucket {
+    fn new(rate: f64, burst: f64, now_ms: u64) -> TokenBucket {
+        TokenBucket { rate: rate, burst: burst, tokens: burst, last_ms: now_ms, strikes: 0 }
+    }
+
+    /* Take cost tokens. If there aren't that many, the seconds until there will be. A rate of 0 never limits */
+    fn take(&mut self, cost: f64, now_ms: u64) -> Result<(), f64> {
+        if self.rate <= 0.0 { return Ok(()) }
+        let elapsed = (now_ms - self.last_ms) as f64 / 1000.0;
+        self.tokens = self.burst.min(self.tokens + elapsed * self.rate);
+        self.last_ms = now_ms;
+        if self.tokens >= self.burst {
+            self.strikes = 0;
+        }
+        if self.tokens >= cost {
+            self.tokens -= cost;
+            Ok(())
+        } else {
+            self.strikes += 1;
+            Err((cost - self.tokens) / self.rate)
+        }
+    }
+}
+
+/*
+ * What a client's handler shares with the rest of the server. Cloning it gives another handle on
+ * the same state, as cloning a Server does.
+ *
+ * clients: The shared clients structure
+ * stats: The server-wide counters
+ * seen: The last activity of every name
+ * maintenance: Whether maintenance mode is on, and who may stay
+ * bans: The banned addresses, which an operator can add to
+ * accounts: The registered names and their passwords
+ * mirrors: The read-only mirror connections, which also get broadcasts
+ * rooms: The rooms and their members
+ * aliases: Other names for rooms, which lead to them
+ * backlog: The most recent broadcasts
+ * motd: The message of the day
+ * config: The server's settings
+ * clock: What the time is
+ * ids: Where every connection's id comes from
+ * shutting_down: Set once the server is shutting down, so its listeners know why accepting fails
+ */
+#[deriving(Clone)]
+struct Shared {
+    clients: Clients,
+    stats: Arc<Stats>,
+    seen: LastSeen,
+    maintenance: Arc<RWLock<Maintenance>>,
+    bans: Bans,
+    accounts: AccountStore,
+    mirrors: Mirrors,
+    rooms: Rooms,
+    aliases: Aliases,
+    backlog: Backlog,
+    motd: Motd,
+    config: Config,
+    clock: SharedClock,
+    ids: Ids,
+    shutting_down: Arc<AtomicBool>
+}
+
+/*
+ * The client receives
+ *
+ * id: To be able to find itself in the client structure
+ * conn: Its connection number, which other clients know it by
+ * stream: The TCP stream to read from
+ * shared: What it shares with the rest of the server
+ * bcast: A receiver to receive broadcasts and other events
+ * rec: Where to record raw input to, if recording is enabled
+ */
+fn handle_client(id: Id, conn: uint, mut stream: Transport, shared: Shared, bcast: Receiver<Event>, rec: Option<File>) {
+    let Shared { clients, stats, seen, maintenance, bans, accounts, mirrors, rooms, aliases, backlog, motd, config, clock, .. } = shared.clone();
+    let mut buffer = [0u8, ..1024*16];
+    let peer = stream.peer_name().ok().map(|addr| addr.ip);
+    let tag = client_tag(&id, peer);
+    log!(LogInfo, tag.as_slice(), "connected as #{}", conn);
+    let mut sc = stream.clone();
+    let (out, outgoing) = sync_channel(COMMAND_QUEUE);
+    sc.control = Some(out.clone());
+    let mut name = "".to_string();
+    /* The token and send time of an unanswered LAG ping */
+    let mut lag_ping: Option<(String, u64)> = None;
+    /* Whether the client has given OPER the operator password */
+    let mut oper = false;
+    /* The account the client has logged in to, whose name it may take */
+    let mut account: Option<String> = None;
+    let mut bucket = TokenBucket::new(config.rate_limit, config.rate_burst, clock.now_ms());
+    let (tx, rx) = sync_channel(COMMAND_QUEUE);
+    /* The handler says it has dealt with each PROTO here, taking the new mode or not */
+    let (proto_tx, proto_done) = channel();
+
+    /*
+     * Spawn reader
+     *
+     * 1) Splits what it reads into lines and parses them
+     * 2) Quits when (a) the QUIT message is received, (b) a read or write error is detected,
+     *    (c) the handler has gone away, (d) nothing has been read for the idle timeout or (e) a
+     *    keepalive PING goes unanswered
+     * 3) Blocks on a full command queue, so a client can't outrun its handler
+     * 4) Sends a keepalive PING when nothing has been heard for a while, and takes the PONG to it
+     *    itself. Answering keepalives doesn't count as activity for the idle timeout.
+     * 5) Never writes to the client itself: its replies go to the handler as Outgoing
+     */
+    let (max_line, idle_timeout) = (config.max_line, config.idle_timeout);
+    let (keepalive_interval, keepalive_grace) = (config.keepalive_interval, config.keepalive_grace);
+    let (tag_cln, stats_cln, clock_cln) = (tag.clone(), stats.clone(), clock.clone());
+    spawn(proc() {
+        let (tag, clock) = (tag_cln, clock_cln);
+        let mut lines = LineReader::new(max_line);
+        let mut rec = Recording::new(rec, max_line);
+        let (mut last_input, mut last_heard) = (clock.now_ms(), clock.now_ms());
+        /* The token and send time of an unanswered keepalive PING */
+        let mut keepalive: Option<(String, u64)> = None;
+        loop {
+            /* The timeout is a deadline, so it is worked out again before every read */
+            let mut deadline = None;
+            if idle_timeout > 0 {
+                deadline = Some(last_input + idle_timeout * 1000);
+            }
+            let ping_due = match keepalive {
+                Some((_, sent))                => Some(sent + keepalive_grace * 1000),
+                None if keepalive_interval > 0 => Some(last_heard + keepalive_interval * 1000),
+                None                           => None
+            };
+            deadline = match (deadline, ping_due) {
+                (Some(a), Some(b)) => Some(std::cmp::min(a, b)),
+                (a, b)             => a.or(b)
+            };
+            let now = clock.now_ms();
+            sc.set_read_timeout(deadline.map(|d| if d > now { d - now } else { 1 }));
+
+            match sc.read(buffer) {
+                Ok(n)  => {
+                    rec.record(buffer.slice(0, n));
+                    BYTES_IN.fetch_add(n, Relaxed);
+                    last_heard = clock.now_ms();
+                    let mut quit = false;
+                    let complete = lines.feed(buffer.slice(0, n));
+                    /* Part of a line is activity too, as long as it isn't only keepalive answers */
+                    let mut active = complete.is_empty();
+                    for line in complete.into_iter() {
+                        let res = match line {
+                            TooLong => {
+                                active = true;
+                                relay(&out, format!("TOOLONG {}\r\n", max_line))
+                            },
+                            Complete(line) => {
+                                trace(&id, "<-", line.as_slice());
+                                /* After PROTO JSON lines are JSON objects, which are turned back into lines to parse */
+                                let line = if sc.json.load(SeqCst) { json_to_line(line.as_slice()).unwrap_or(line) } else { line };
+                                match String::from_utf8(line) {
+                                    Err(line) => {
+                                        active = true;
+                                        log!(LogDebug, tag.as_slice(), "not UTF-8: {}", String::from_utf8_lossy(line.as_slice()));
+                                        stats_cln.parse_errors.fetch_add(1, SeqCst);
+                                        relay(&out, "BADENCODING\r\n".to_string())
+                                    },
+                                    Ok(line) => match process_input(line.as_slice()) {
+                                        Some(Pong(ref token)) if keepalive.as_ref().map_or(false, |&(ref t, _)| t == token) => {
+                                            keepalive = None;
+                                            Ok(())
+                                        },
+                                        Some(Quit) => {
+                                            quit = true;
+                                            break;
+                                        },
+                                        Some(m) => {
+                                            active = true;
+                                            let proto = match m { Proto(_) => true, _ => false };
+                                            /*
+                                             * Only the handler knows whether PROTO is taken (it may be disabled or
+                                             * throttled), and how the next line is read depends on it
+                                             */
+                                            if tx.send_opt(m).is_err() || (proto && proto_done.recv_opt().is_err()) {
+                                                quit = true;
+                                                break;
+                                            } else {
+                                                Ok(())
+                                            }
+                                        },
+                                        None => {
+                                            active = true;
+                                            let line = printable(line.as_slice());
+                                            log!(LogDebug, tag.as_slice(), "malformed command: {}", line);
+                                            stats_cln.parse_errors.fetch_add(1, SeqCst);
+                                            relay(&out, format!("ERROR {}\r\n", line))
+                                        }
+                                    }
+                                }
+                            }
+                        };
+                        match res {
+                            Ok(()) => (),
+                            Err(e) => {
+                                log!(LogWarn, tag.as_slice(), "{}. Quitting.", e);
+                                quit = true;
+                                break;
+                            }
+                        }
+                    }
+                    if quit {
+                        tx.send_opt(Quit).ok();
+                        break;
+                    }
+                    if active {
+                        last_input = last_heard;
+                    }
+                },
+                Err(ref e) if e.kind == TimedOut => {
+                    let now = clock.now_ms();
+                    if idle_timeout > 0 && now >= last_input + idle_timeout * 1000 {
+                        /* Leaving anyway, so a failure to say why doesn't matter */
+                        relay(&out, format!("IDLE {}\r\n", idle_timeout)).ok();
+                        log!(LogInfo, tag.as_slice(), "idle for {}s. Quitting.", idle_timeout);
+                        tx.send_opt(Quit).ok();
+                        break;
+                    }
+                    match keepalive.as_ref().map(|&(_, sent)| sent) {
+                        Some(sent) if now >= sent + keepalive_grace * 1000 => {
+                            log!(LogInfo, tag.as_slice(), "did not answer PING within {}s. Quitting.", keepalive_grace);
+                            tx.send_opt(Quit).ok();
+                            break;
+                        },
+                        None if keepalive_interval > 0 && now >= last_heard + keepalive_interval * 1000 => {
+                            let token = format!("{:08x}", task_rng().gen::<u32>());
+                            if relay(&out, format!("PING {}\r\n", token)).is_err() {
+                                log!(LogInfo, tag.as_slice(), "unreachable for keepalive. Quitting.");
+                                tx.send_opt(Quit).ok();
+                                break;
+                            }
+                            keepalive = Some((token, now));
+                        },
+                        _ => ()
+                    }
+                },
+                Err(e) => {
+                    log!(LogWarn, tag.as_slice(), "Received {}. Quitting.", e);
+                    tx.send_opt(Quit).ok();
+                    break;
+                }
+            }
+        }
+        rec.finish();
+    });
+
+    /*
+     * Greet the client with the message of the day, and catch it up on what was said just before it
+     * came; the loop notices if this fails
+     */
+    let mut greeting = motd_lines(&motd);
+    greeting.push_str(history_lines(&backlog, config.history_on_connect).as_slice());
+    if !greeting.is_empty() {
+        reply(&mut stream, &id, greeting.as_bytes()).ok();
+    }
+
+    loop {
+        let res = select! {
+            meth = rx.recv_opt() => {
+                let started = time::precise_time_ns();
+                let verb = match meth { Ok(ref m) => m.verb(), Err(()) => "" };
+                let wait = match meth {
+                    Ok(ref m) => bucket.take(m.cost(), clock.now_ms()).err(),
+                    Err(())   => None
+                };
+                let res = match meth {
+                    /* The reader is gone either way */
+                    Ok(Quit) | Err(()) => break,
+                    Ok(ref m) if config.disabled.iter().any(|v| v.as_slice() == m.verb()) => {
+                        reply(&mut stream, &id, format!("ERR_DISABLED {}\r\n", m.verb()).as_bytes())
+                    },
+                    Ok(_) if wait.is_some() => if config.throttle_strikes > 0 && bucket.strikes >= config.throttle_strikes {
+                        reply(&mut stream, &id, b"KICKED Flooding\r\n").ok();
+                        log!(LogWarn, tag.as_slice(), "kept flooding. Disconnecting.");
+                        break;
+                    } else {
+                        let seconds = std::cmp::max(1, wait.unwrap().ceil() as u64);
+                        reply(&mut stream, &id, format!("THROTTLED {}\r\n", seconds).as_bytes())
+                    },
+                    Ok(Who(pattern)) => {
+                        let (c, seen) = (clients.read(), seen.read());
+                        /* Named clients by name, then the unnamed ones. A pattern only matches names */
+                        let mut users: Vec<(&Id, &Outbox, &String)> = c.by_id.iter()
+                            .filter(|&(_, &(_, ref user))| match pattern {
+                                Some(ref pattern) => !user.is_empty() && glob_match(pattern.as_slice(), user.as_slice()),
+                                None => true
+                            })
+                            .map(|(member, &(ref ch, ref user))| (member, ch, user))
+                            .collect();
+                        users.sort_by(|&(_, _, a), &(_, _, b)| (a.is_empty(), fold(a.as_slice())).cmp(&(b.is_empty(), fold(b.as_slice()))));
+                        let now = clock.now_ms();
+                        let mut lines = String::new();
+                        for &(member, ch, user) in users.iter() {
+                            let (shown, since, state) = if user.is_empty() {
+                                ("*".to_string(), c.connected_ms(member), "unnamed".to_string())
+                            } else {
+                                match seen.find(&fold(user.as_slice())) {
+                                    Some(&(ms, ref what)) => (user.clone(), ms, what.to_string().into_ascii_lower()),
+                                    None => (user.clone(), c.connected_ms(member), "connected".to_string())
+                                }
+                            };
+                            let address = ch.peer().map_or("-".to_string(), |ip| ip.to_string());
+                            lines.push_str(format!("WHOREPLY {} {} {} {}\r\n", shown, (now - since) / 1000, address, state).as_slice());
+                        }
+                        lines.push_str("ENDWHO\r\n");
+                        reply(&mut stream, &id, lines.as_bytes())
+                    },
+                    Ok(Whox) => {
+                        let (rooms, c, seen) = (rooms.read(), clients.read(), seen.read());
+                        let mut users: Vec<(&String, &Id)> = c.by_name.iter().collect();
+                        users.sort();
+                        let now = clock.now_ms();
+                        let mut lines = String::new();
+                        for &(key, member) in users.iter() {
+                            let user = match c.by_id.find(member) {
+                                Some(&(_, ref user)) => user,
+                                None => continue
+                            };
+                            let joined = rooms_of(&*rooms, member);
+                            let (state, idle) = match seen.find(key) {
+                                Some(&(ms, ref what)) => (what.to_string().into_ascii_lower(), (now - ms) / 1000),
+                                None                  => ("connected".to_string(), 0)
+                            };
+                            lines.push_str(format!("WHOX {}\t{}\t{}\t{}\t{}\r\n", user, state,
+                                                   if joined.is_empty() { "-".to_string() } else { joined.connect(",") },
+                                                   idle, c.conn(member)).as_slice());
+                        }
+                        lines.push_str("ENDWHOX\r\n");
+                        reply(&mut stream, &id, lines.as_bytes())
+                    },
+                    Ok(Name(ref wanted)) if fold(wanted.as_slice()) == fold(ANON_NAME)
+                                            || (accounts.read().is_registered(wanted.as_slice())
+                                                && account.as_ref().map(|a| fold(a.as_slice())) != Some(fold(wanted.as_slice()))) => {
+                        reply(&mut stream, &id, format!("NAMERESERVED {}\r\n", wanted).as_bytes())
+                    },
+                    Ok(Name(wanted)) => {
+                        let renamed = clients.write().rename(&id, wanted.as_slice());
+                        match renamed {
+                            NameGiven(given) => {
+                                let first = name.is_empty();
+                                named(&shared, conn, name.as_slice(), given.as_slice());
+                                name = given;
+                                let mut lines = if name == wanted { String::new() } else { format!("NAMED {}\r\n", name) };
+                                if first {
+                                    lines.push_str(auto_join(&rooms, &clients, &aliases, config.auto_join.as_slice(), &id).as_slice());
+                                }
+                                if lines.is_empty() { Ok(()) } else { reply(&mut stream, &id, lines.as_bytes()) }
+                            },
+                            NameTaken => reply(&mut stream, &id, format!("NAMEINUSE {}\r\n", wanted).as_bytes()),
+                            Unregistered => Err(NotRegistered(id))
+                        }
+                    },
+                    Ok(Broadcast(msg)) => if name.is_empty() {
+                        reply(&mut stream, &id, b"NONAME\r\n")
+                    } else {
+                        /* A client whose handler has just ended will remove itself, so failed sends are fine */
+                        let c = clients.read();
+                        for &(ref client, _) in c.by_id.values() {
+                            stats.deliver(client, Message(name.clone(), msg.clone()));
+                        }
+                        log!(LogDebug, tag.as_slice(), "broadcast to {} client(s)", c.by_id.len());
+                        for &(ref mirror, ref room) in mirrors.read().values() {
+                            if room.is_none() {
+                                stats.deliver(mirror, Message(name.clone(), msg.clone()));
+                            }
+                        }
+                        stats.broadcasts.fetch_add(1, SeqCst);
+                        remember(&backlog, config.history_size, clock.now_ms(), name.as_slice(), msg.as_slice());
+                        saw(&seen, clock.now_ms(), name.as_slice(), Spoke);
+                        Ok(())
+                    },
+                    Ok(Proto(mode)) => {
+                        stream.json.store(mode.as_slice() == "JSON", SeqCst);
+                        reply(&mut stream, &id, format!("PROTO {}\r\n", mode).as_bytes())
+                    },
+                    Ok(Help(verb)) => {
+                        /* Straight from the protocol tables, so it can't disagree with --dump-protocol */
+                        let lines = match verb {
+                            None => {
+                                let mut lines = String::new();
+                                for v in VERBS.iter().filter(|v| available(v, oper, &config)) {
+                                    lines.push_str(usage(v).as_slice());
+                                }
+                                lines.push_str("ENDHELP\r\n");
+                                lines
+                            },
+                            Some(verb) => match VERBS.iter().find(|v| v.verb == verb.as_slice() && available(v, oper, &config)) {
+                                Some(v) => format!("{}HELPTEXT {}\r\nENDHELP\r\n", usage(v), v.summary),
+                                None    => format!("NOSUCHVERB {}\r\n", verb)
+                            }
+                        };
+                        reply(&mut stream, &id, lines.as_bytes())
+                    },
+                    Ok(History(count)) => {
+                        let mut lines = history_lines(&backlog, count.unwrap_or(config.history_size));
+                        lines.push_str("ENDHISTORY\r\n");
+                        reply(&mut stream, &id, lines.as_bytes())
+                    },
+                    Ok(Msg(_, _)) if name.is_empty() => reply(&mut stream, &id, b"NONAME\r\n"),
+                    Ok(Msg(target, text)) => match find_client(&clients, target.as_slice()) {
+                        Some(ch) => {
+                            stats.deliver(&ch, Private(name.clone(), text));
+                            saw(&seen, clock.now_ms(), name.as_slice(), Spoke);
+                            Ok(())
+                        },
+                        None => reply(&mut stream, &id, format!("NOSUCHUSER {}\r\n", target).as_bytes())
+                    },
+                    Ok(Join(wanted)) => {
+                        let (room, mut line) = match aliases.read().resolve(wanted.as_slice()) {
+                            Some(room) => {
+                                let notice = format!("NOTICE {} is now {}\r\n", wanted, room);
+                                (room, notice)
+                            },
+                            None       => (wanted, String::new())
+                        };
+                        line.push_str(join_room(&rooms, &clients, room.as_slice(), &id).as_slice());
+                        match account {
+                            Some(ref account) => save_rooms(&accounts, account.as_slice(), &rooms, &id),
+                            None => ()
+                        }
+                        reply(&mut stream, &id, line.as_bytes())
+                    },
+                    Ok(Part(room)) => if leave_room(&mut *rooms.write(), resolve_room(&aliases, room.as_slice()).as_slice(), &id) {
+                        match account {
+                            Some(ref account) => save_rooms(&accounts, account.as_slice(), &rooms, &id),
+                            None => ()
+                        }
+                        reply(&mut stream, &id, format!("PARTED {}\r\n", room).as_bytes())
+                    } else {
+                        reply(&mut stream, &id, format!("NOTINROOM {}\r\n", room).as_bytes())
+                    },
+                    Ok(List) => {
+                        let mut lines = String::new();
+                        let rooms = rooms.read();
+                        let mut listed: Vec<&Room> = rooms.values().collect();
+                        listed.sort_by(|a, b| a.name.cmp(&b.name));
+                        for room in listed.into_iter() {
+                            lines.push_str(format!("ROOM {} {}\r\n", room.name, room.members.len()).as_slice());
+                        }
+                        lines.push_str("ENDROOMS\r\n");
+                        reply(&mut stream, &id, lines.as_bytes())
+                    },
+                    Ok(Say(_, _)) if name.is_empty() => reply(&mut stream, &id, b"NONAME\r\n"),
+                    Ok(Say(room, text)) => {
+                        let room = resolve_room(&aliases, room.as_slice());
+                        let key = fold(room.as_slice());
+                        let found = match rooms.read().find(&key) {
+                            Some(found) if found.members.contains(&id) => Some((found.name.clone(), found.members.clone(), found.anonymous)),
+                            _ => None
+                        };
+                        match found {
+                            Some((room, members, anonymous)) => {
+                                let from = if anonymous {
+                                    /* Whatever the log level, since this is the only record of who said it */
+                                    write_log(LogInfo, tag.as_slice(), format!("audit: {} said in anonymous room {}: {}", name, room, text));
+                                    ANON_NAME.to_string()
+                                } else {
+                                    name.clone()
+                                };
+                                let c = clients.read();
+                                for member in members.iter() {
+                                    match c.by_id.find(member) {
+                                        Some(&(ref ch, _)) => stats.deliver(ch, RoomMessage(room.clone(), from.clone(), text.clone())),
+                                        None => ()
+                                    }
+                                }
+                                for &(ref mirror, ref watched) in mirrors.read().values() {
+                                    if watched.as_ref() == Some(&key) {
+                                        stats.deliver(mirror, RoomMessage(room.clone(), from.clone(), text.clone()));
+                                    }
+                                }
+                                /* SEEN would give away who is speaking in an anonymous room */
+                                if !anonymous {
+                                    saw(&seen, clock.now_ms(), name.as_slice(), Spoke);
+                                }
+                                Ok(())
+                            },
+                            None => reply(&mut stream, &id, format!("NOTINROOM {}\r\n", room).as_bytes())
+                        }
+                    },
+                    Ok(Topic(room, None)) => {
+                        let room = resolve_room(&aliases, room.as_slice());
+                        let line = match rooms.read().find(&fold(room.as_slice())) {
+                            Some(&Room { name: ref found, topic: Some((ref by, ref topic)), .. }) => format!("TOPIC {} {} {}\r\n", found, by, topic),
+                            Some(found) => format!("NOTOPIC {}\r\n", found.name),
+                            None => format!("NOSUCHROOM {}\r\n", room)
+                        };
+                        reply(&mut stream, &id, line.as_bytes())
+                    },
+                    Ok(Topic(_, Some(_))) if name.is_empty() => reply(&mut stream, &id, b"NONAME\r\n"),
+                    Ok(Topic(room, Some(text))) => {
+                        let room = resolve_room(&aliases, room.as_slice());
+                        let found = match rooms.write().find_mut(&fold(room.as_slice())) {
+                            Some(found) if found.members.contains(&id) => {
+                                found.topic = Some((name.clone(), text.clone()));
+                                Some((found.name.clone(), found.members.clone()))
+                            },
+                            _ => None
+                        };
+                        match found {
+                            Some((room, members)) => {
+                                let c = clients.read();
+                                for member in members.iter() {
+                                    match c.by_id.find(member) {
+                                        Some(&(ref ch, _)) => stats.deliver(ch, TopicChanged(room.clone(), name.clone(), text.clone())),
+                                        None => ()
+                                    }
+                                }
+                                Ok(())
+                            },
+                            None => reply(&mut stream, &id, format!("NOTINROOM {}\r\n", room).as_bytes())
+                        }
+                    },
+                    Ok(Roll(n, sides)) => reply(&mut stream, &id, roll(n, sides).as_bytes()),
+                    Ok(Choose(options)) => {
+                        let choice = task_rng().choose(options.as_slice()).unwrap();
+                        reply(&mut stream, &id, format!("CHOSE {}\r\n", choice).as_bytes())
+                    },
+                    Ok(Seen(who)) => {
+                        let line = match seen.read().find(&fold(who.as_slice())) {
+                            Some(&(ms, ref what)) => {
+                                let ago = (clock.now_ms() - ms) / 1000;
+                                format!("SEEN {} {} {}\r\n", who, ago, what.to_string().into_ascii_lower())
+                            },
+                            None => format!("NOTSEEN {}\r\n", who)
+                        };
+                        reply(&mut stream, &id, line.as_bytes())
+                    },
+                    Ok(Lag) => {
+                        let token = format!("{:08x}", task_rng().gen::<u32>());
+                        lag_ping = Some((token.clone(), clock.now_ms()));
+                        reply(&mut stream, &id, format!("PING {}\r\n", token).as_bytes())
+                    },
+                    Ok(Pong(token)) => match lag_ping.take() {
+                        Some((ref expected, sent)) if *expected == token => {
+                            /* Queue behind whatever else is waiting for us, to see how far behind we are */
+                            let now = clock.now_ms();
+                            match clients.read().by_id.find(&id) {
+                                Some(&(ref ch, _)) => { stats.deliver(ch, Probe(now, now - sent)); Ok(()) },
+                                None               => Err(NotRegistered(id))
+                            }
+                        },
+                        /* Stale or unsolicited, so there is nothing to measure */
+                        other => { lag_ping = other; Ok(()) }
+                    },
+                    Ok(Oper(password)) => match config.oper_password {
+                        Some(ref expected) if *expected == password => {
+                            oper = true;
+                            log!(LogInfo, tag.as_slice(), "is now an operator");
+                            reply(&mut stream, &id, b"OPERED\r\n")
+                        },
+                        _ => reply(&mut stream, &id, b"BADPASSWORD\r\n")
+                    },
+                    Ok(KickUser(..)) | Ok(Ban(_)) | Ok(AliasRoom(..)) | Ok(RenameRoom(..)) | Ok(Rehash) | Ok(Anonymous(..)) if !oper => {
+                        reply(&mut stream, &id, b"NOTOPER\r\n")
+                    },
+                    Ok(KickUser(target, reason)) => match find_client(&clients, target.as_slice()) {
+                        Some(ch) => {
+                            log!(LogInfo, tag.as_slice(), "kicked {}: {}", target, reason);
+                            stats.deliver(&ch, Kick(reason));
+                            Ok(())
+                        },
+                        None => reply(&mut stream, &id, format!("NOSUCHUSER {}\r\n", target).as_bytes())
+                    },
+                    Ok(Ban(target)) => {
+                        let ip = match from_str::<IpAddr>(target.as_slice()) {
+                            Some(ip) => Some(ip),
+                            None     => find_client(&clients, target.as_slice()).and_then(|ch| ch.peer())
+                        };
+                        match ip {
+                            Some(ip) => {
+                                {
+                                    let mut bans = bans.write();
+                                    if !bans.contains(&ip) { bans.push(ip); }
+                                }
+                                log!(LogInfo, tag.as_slice(), "banned {}", ip);
+                                for &(ref ch, _) in clients.read().by_id.values() {
+                                    if ch.peer() == Some(ip) {
+                                        stats.deliver(ch, Kick(BAN_REASON.to_string()));
+                                    }
+                                }
+                                reply(&mut stream, &id, format!("BANNED {}\r\n", ip).as_bytes())
+                            },
+                            None => reply(&mut stream, &id, format!("NOSUCHUSER {}\r\n", target).as_bytes())
+                        }
+                    },
+                    Ok(AliasRoom(alias, room)) => if rooms.read().contains_key(&fold(alias.as_slice())) {
+                        reply(&mut stream, &id, format!("ROOMEXISTS {}\r\n", alias).as_bytes())
+                    } else {
+                        save_alias(&aliases, alias.as_slice(), room.as_slice());
+                        log!(LogInfo, tag.as_slice(), "aliased room {} to {}", alias, room);
+                        reply(&mut stream, &id, format!("ALIASED {} {}\r\n", alias, room).as_bytes())
+                    },
+                    Ok(Anonymous(room, on)) => {
+                        let room = resolve_room(&aliases, room.as_slice());
+                        let found = match rooms.write().find_mut(&fold(room.as_slice())) {
+                            Some(found) => {
+                                found.anonymous = on;
+                                Some((found.name.clone(), found.members.clone()))
+                            },
+                            None => None
+                        };
+                        match found {
+                            Some((room, members)) => {
+                                let c = clients.read();
+                                for member in members.iter().filter(|&member| *member != id) {
+                                    match c.by_id.find(member) {
+                                        Some(&(ref ch, _)) => stats.deliver(ch, AnonymousChanged(room.clone(), on)),
+                                        None => ()
+                                    }
+                                }
+                                log!(LogInfo, tag.as_slice(), "switched anonymous mode {} in room {}", on_off(on), room);
+                                reply(&mut stream, &id, format!("ANONYMOUS {} {}\r\n", room, on_off(on)).as_bytes())
+                            },
+                            None => reply(&mut stream, &id, format!("NOSUCHROOM {}\r\n", room).as_bytes())
+                        }
+                    },
+                    Ok(Rehash) => match read_motd(&config.motd_file) {
+                        Ok(lines) => {
+                            let n = lines.len();
+                            *motd.write() = lines;
+                            log!(LogInfo, tag.as_slice(), "reread the MOTD, {} lines", n);
+                            reply(&mut stream, &id, format!("REHASHED {}\r\n", n).as_bytes())
+                        },
+                        Err(e) => {
+                            log!(LogWarn, tag.as_slice(), "could not reread the MOTD: {}", e);
+                            reply(&mut stream, &id, format!("REHASHFAILED {}\r\n", e).as_bytes())
+                        }
+                    },
+                    Ok(RenameRoom(old, new)) => {
+                        let renamed = {
+                            let mut rooms = rooms.write();
+                            /* A room may be renamed to another casing of its own name */
+                            if fold(new.as_slice()) != fold(old.as_slice()) && rooms.contains_key(&fold(new.as_slice())) {
+                                Err(format!("ROOMEXISTS {}\r\n", new))
+                            } else {
+                                match rooms.pop(&fold(old.as_slice())) {
+                                    Some(room) => {
+                                        let members = room.members.clone();
+                                        rooms.insert(fold(new.as_slice()), Room { name: new.clone(), ..room });
+                                        Ok(members)
+                                    },
+                                    None => Err(format!("NOSUCHROOM {}\r\n", old))
+                                }
+                            }
+                        };
+                        match renamed {
+                            Ok(members) => {
+                                save_alias(&aliases, old.as_slice(), new.as_slice());
+                                for entry in mirrors.write().values_mut() {
+                                    let (_, ref mut watched) = *entry;
+                                    if watched.as_ref() == Some(&fold(old.as_slice())) {
+                                        *watched = Some(fold(new.as_slice()));
+                                    }
+                                }
+                                let c = clients.read();
+                                for member in members.iter().filter(|&member| *member != id) {
+                                    match c.by_id.find(member) {
+                                        Some(&(ref ch, _)) => stats.deliver(ch, RoomRenamed(old.clone(), new.clone())),
+                                        None => ()
+                                    }
+                                }
+                                log!(LogInfo, tag.as_slice(), "renamed room {} to {}", old, new);
+                                reply(&mut stream, &id, format!("ROOMRENAMED {} {}\r\n", old, new).as_bytes())
+                            },
+                            Err(line) => reply(&mut stream, &id, line.as_bytes())
+                        }
+                    },
+                    Ok(Register(wanted, password)) => {
+                        let owner = clients.read().by_name.find(&fold(wanted.as_slice())).map(|owner| *owner);
+                        let registered = {
+                            let mut store = accounts.write();
+                            if store.path.is_none() {
+                                Err("NOACCOUNTS\r\n".to_string())
+                            } else if store.is_registered(wanted.as_slice()) {
+                                Err(format!("ACCOUNTEXISTS {}\r\n", wanted))
+                            } else if fold(wanted.as_slice()) == fold(ANON_NAME) {
+                                Err(format!("NAMERESERVED {}\r\n", wanted))
+                            } else if owner.map_or(false, |owner| owner != id) {
+                                Err(format!("NAMEINUSE {}\r\n", wanted))
+                            } else {
+                                store.register(wanted.as_slice(), password.as_slice()).map_err(|e| {
+                                    log!(LogError, tag.as_slice(), "Saving the account {}: {}", wanted, e);
+                                    "NOACCOUNTS\r\n".to_string()
+                                })
+                            }
+                        };
+                        match registered {
+                            Ok(()) => {
+                                log!(LogInfo, tag.as_slice(), "registered {}", wanted);
+                                log_in(&shared, &id, conn, &mut name, &mut account, wanted.as_slice(), "REGISTERED")
+                                    .and_then(|lines| reply(&mut stream, &id, lines.as_bytes()))
+                            },
+                            Err(line) => reply(&mut stream, &id, line.as_bytes())
+                        }
+                    },
+                    Ok(Login(wanted, password)) => if !accounts.read().check(wanted.as_slice(), password.as_slice()) {
+                        reply(&mut stream, &id, b"BADLOGIN\r\n")
+                    } else {
+                        log_in(&shared, &id, conn, &mut name, &mut account, wanted.as_slice(), "LOGGEDIN")
+                            .and_then(|lines| reply(&mut stream, &id, lines.as_bytes()))
+                    }
+                };
+                if verb == "PROTO" {
+                    proto_tx.send_opt(()).ok();
+                }
+                stats.record_latency(verb, (time::precise_time_ns() - started) / 1000);
+                res
+            },
+            ev = bcast.recv_opt() => {
+                if ev.is_ok() { stats.queued_events.fetch_sub(1, SeqCst); }
+                match ev {
+                    Ok(ev @ Message(..)) | Ok(ev @ Private(..)) | Ok(ev @ RoomMessage(..)) => {
+                        reply(&mut stream, &id, message_line(&config, clock.now_ms(), &ev).unwrap().as_bytes())
+                    },
+                    Ok(Joined(who, n)) => reply(&mut stream, &id, format!("JOINED {} {}\r\n", who, n).as_bytes()),
+                    Ok(Left(who, n)) => reply(&mut stream, &id, format!("LEFT {} {}\r\n", who, n).as_bytes()),
+                    Ok(Renamed(old, new, n)) => reply(&mut stream, &id, format!("RENAMED {} {} {}\r\n", old, new, n).as_bytes()),
+                    Ok(RoomRenamed(old, new)) => reply(&mut stream, &id, format!("ROOMRENAMED {} {}\r\n", old, new).as_bytes()),
+                    Ok(TopicChanged(room, who, topic)) => {
+                        reply(&mut stream, &id, format!("TOPICCHANGED {} {} {}\r\n", room, who, topic).as_bytes())
+                    },
+                    Ok(AnonymousChanged(room, on)) => {
+                        reply(&mut stream, &id, format!("ANONYMOUS {} {}\r\n", room, on_off(on)).as_bytes())
+                    },
+                    Ok(Announce(lines)) => {
+                        /* One write, so the lines of a block arrive together */
+                        let mut block = String::new();
+                        for line in lines.iter() {
+                            block.push_str(format!("SERVER {}\r\n", line).as_slice());
+                        }
+                        reply(&mut stream, &id, block.as_bytes())
+                    },
+                    Ok(Kick(reason)) => {
+                        /* Leaving anyway, so a failure to say why doesn't matter */
+                        reply(&mut stream, &id, format!("KICKED {}\r\n", reason).as_bytes()).ok();
+                        log!(LogInfo, tag.as_slice(), "kicked: {}", reason);
+                        break;
+                    },
+                    Ok(Closing(reason)) => {
+                        reply(&mut stream, &id, format!("SERVERCLOSING {}\r\n", reason).as_bytes()).ok();
+                        break;
+                    },
+                    Ok(MaintenanceOn(message)) => if maintenance.read().refuse(peer).is_some() {
+                        reply(&mut stream, &id, format!("MAINTENANCE {}\r\n", message).as_bytes()).ok();
+                        log!(LogInfo, tag.as_slice(), "disconnected for maintenance");
+                        break;
+                    } else {
+                        Ok(())
+                    },
+                    Ok(Probe(queued, rtt)) => {
+                        let line = format!("LAG {} {}\r\n", rtt, clock.now_ms() - queued);
+                        reply(&mut stream, &id, line.as_bytes())
+                    },
+                    /* Our sender lives in the registry, so losing it means we were unregistered */
+                    Err(()) => Err(NotRegistered(id))
+                }
+            },
+            written = outgoing.recv_opt() => match written {
+                Ok(OutLines(lines)) => reply(&mut stream, &id, lines.as_bytes()),
+                Ok(OutFrame(opcode, payload)) => stream.send_frame(opcode, payload.as_slice()).map_err(WriteFailed),
+                /* The reader is gone */
+                Err(()) => break
+            }
+        };
+
+        match res {
+            Ok(()) => (),
+            Err(e) => {
+                log!(LogWarn, tag.as_slice(), "{}. Disconnecting.", e);
+                break;
+            }
+        }
+    }
+
+    /* What the reader had written on its way out, such as IDLE or the answer to a close frame */
+    loop {
+        match outgoing.try_recv() {
+            Ok(OutLines(lines)) => { reply(&mut stream, &id, lines.as_bytes()).ok(); },
+            Ok(OutFrame(opcode, payload)) => { stream.send_frame(opcode, payload.as_slice()).ok(); },
+            Err(_) => break
+        }
+    }
+    /* Unblocks the reader if we are leaving because of an error */
+    stream.close_read().ok();
+    saw(&seen, clock.now_ms(), name.as_slice(), Disconnected);
+    {
+        let mut rooms = rooms.write();
+        for room in rooms_of(&*rooms, &id).iter() {
+            leave_room(&mut *rooms, room.as_slice(), &id);
+        }
+    }
+    if !clients.write().remove(&id) {
+        log!(LogError, tag.as_slice(), "{} while disconnecting.", NotRegistered(id));
+    } else {
+        stats.registered.fetch_sub(1, SeqCst);
+    }
+    if !name.is_empty() {
+        send_all(&clients, &*stats, Left(name.clone(), conn));
+    }
+    /* Nobody can reach us any more, so whatever is still queued will never be received */
+    while bcast.try_recv().is_ok() {
+        stats.queued_events.fetch_sub(1, SeqCst);
+    }
+    stats.handlers.fetch_sub(1, SeqCst);
+    log!(LogInfo, tag.as_slice(), "disconnected");
+}
+
+/*
+ * Banner art for bigannounce: text rendered in a 3x5 block font, one string per row. Lowercase is
+ * drawn as uppercase and anything the font lacks as '?'.
+ */
+static BANNER_MAX_CHARS: uint = 20;
+
+static FONT: &'static [(char, [&'static str, ..5])] = &[
+    ('A', [" # ", "# #", "###", "# #", "# #"]),
+    ('B', ["## ", "# #", "## ", "# #", "## "]),
+    ('C', [" ##", "#  ", "#  ", "#  ", " ##"]),
+    ('D', ["## ", "# #", "# #", "# #", "## "]),
+    ('E', ["###", "#  ", "## ", "#  ", "###"]),
+    ('F', ["###", "#  ", "## ", "#  ", "#  "]),
+    ('G', [" ##", "#  ", "# #", "# #", " ##"]),
+    ('H', ["# #", "# #", "###", "# #", "# #"]),
+    ('I', ["###", " # ", " # ", " # ", "###"]),
+    ('J', ["  #", "  #", "  #", "# #", " # "]),
+    ('K', ["# #", "# #", "## ", "# #", "# #"]),
+    ('L', ["#  ", "#  ", "#  ", "#  ", "###"]),
+    ('M', ["# #", "###", "###", "# #", "# #"]),
+    ('N', ["## ", "# #", "# #", "# #", "# #"]),
+    ('O', [" # ", "# #", "# #", "# #", " # "]),
+    ('P', ["## ", "# #", "## ", "#  ", "#  "]),
+    ('Q', [" # ", "# #", "# #", "## ", " ##"]),
+    ('R', ["## ", "# #", "## ", "# #", "# #"]),
+    ('S', [" ##", "#  ", " # ", "  #", "## "]),
+    ('T', ["###", " # ", " # ", " # ", " # "]),
+    ('U', ["# #", "# #", "# #", "# #", "###"]),
+    ('V', ["# #", "# #", "# #", "# #", " # "]),
+    ('W', ["# #", "# #", "###", "###", "# #"]),
+    ('X', ["# #", "# #", " # ", "# #", "# #"]),
+    ('Y', ["# #", "# #", " # ", " # ", " # "]),
+    ('Z', ["###", "  #", " # ", "#  ", "###"]),
+    ('0', ["###", "# #", "# #", "# #", "###"]),
+    ('1', [" # ", "## ", " # ", " # ", "###"]),
+    ('2', ["## ", "  #", " # ", "#  ", "###"]),
+    ('3', ["## ", "  #", " # ", "  #", "## "]),
+    ('4', ["# #", "# #", "###", "  #", "  #"]),
+    ('5', ["###", "#  ", "## ", "  #", "## "]),
+    ('6', [" ##", "#  ", "###", "# #", "###"]),
+    ('7', ["###", "  #", " # ", " # ", " # "]),
+    ('8', ["###", "# #", "###", "# #", "###"]),
+    ('9', ["###", "# #", "###", "  #", "## "]),
+    (' ', ["   ", "   ", "   ", "   ", "   "]),
+    ('!', [" # ", " # ", " # ", "   ", " # "]),
+    ('.', ["   ", "   ", "   ", "   ", " # "]),
+    ('-', ["   ", "   ", "###", "   ", "   "]),
+    ('?', ["## ", "  #", " # ", "   ", " # "])
+];
+
+fn glyph(c: char) -> &'static [&'static str, ..5] {
+    let c = c.to_uppercase();
+    for &(g, ref rows) in FONT.iter() {
+        if g == c { return rows }
+    }
+    glyph('?')
+}
+
+fn banner(text: &str) -> Vec<String> {
+    range(0u, 5).map(|row| {
+        let cells: Vec<&str> = text.chars().map(|c| glyph(c)[row]).collect();
+        cells.connect(" ").as_slice().trim_right().to_string()
+    }).collect()
+}
+
+/*
+ * Message lines. FROM, PRIVFROM and SAYFROM are rendered from templates in the configuration, in
+ * which {name}, {text}, {room}, {time} (seconds since the epoch, now_ms when the line is sent) and {server}
+ * (the server_tag) are filled in. Every path that sends one of them goes through here, so clients
+ * and mirrors always see the same lines.
+ */
+fn message_line(config: &Config, now_ms: u64, ev: &Event) -> Option<String> {
+    let (template, room, name, text) = match *ev {
+        Message(ref name, ref text)               => (&config.format_from, "", name, text),
+        Private(ref name, ref text)               => (&config.format_privfrom, "", name, text),
+        RoomMessage(ref room, ref name, ref text) => (&config.format_sayfrom, room.as_slice(), name, text),
+        _                                         => return None
+    };
+    let time = (now_ms / 1000).to_string();
+    let fields = [("name", name.as_slice()), ("text", text.as_slice()), ("room", room),
+                  ("time", time.as_slice()), ("server", config.server_tag.as_slice())];
+    /* Templates are checked when they are configured, so every placeholder has a value */
+    render(template.as_slice(), &fields).ok().map(|line| format!("{}\r\n", line))
+}
+
+/* Fill in the {placeholders} of a template, or say which one there is no value for */
+fn render(template: &str, fields: &[(&str, &str)]) -> Result<String, String> {
+    let mut line = String::new();
+    let mut rest = template;
+    loop {
+        let open = match rest.find('{') {
+            Some(open) => open,
+            None => {
+                line.push_str(rest);
+                return Ok(line);
+            }
+        };
+        line.push_str(rest.slice_to(open));
+        rest = rest.slice_from(open + 1);
+        let close = match rest.find('}') {
+            Some(close) => close,
+            None => return Err("unclosed {".to_string())
+        };
+        match fields.iter().find(|&&(field, _)| field == rest.slice_to(close)) {
+            Some(&(_, value)) => line.push_str(value),
+            None => return Err(format!("unknown placeholder {{{}}}", rest.slice_to(close)))
+        }
+        rest = rest.slice_from(close + 1);
+    }
+}
+
+/* A template for a message line, which must start with its reply so clients can still tell them apart */
+fn message_format(key: &str, reply: &str, value: &str) -> Result<String, String> {
+    if !value.starts_with(format!("{} ", reply).as_slice()) {
+        return Err(format!("{} must start with \"{} \"", key, reply));
+    }
+    let fields = [("name", ""), ("text", ""), ("room", ""), ("time", ""), ("server", "")];
+    match render(value, &fields) {
+        Ok(_)  => Ok(value.to_string()),
+        Err(e) => Err(format!("{}: {}", key, e))
+    }
+}
+
+/*
+ * The read-only mirror listener, for projecting the chat on a screen at an event. Connections get
+ * every broadcast as a FROM line. The only command accepted is QUIT; anything else is answered with
+ * READONLY, and nothing else reaches the rest of the server.
+ */
+fn mirror_listener(mut acpt: TcpAcceptor, shared: Shared) {
+    loop {
+        match acpt.accept() {
+            Ok(st) => {
+                let id = shared.ids.lock().next_id();
+                let (outbox, rx) = Outbox::new(id, &st, &shared.config);
+                shared.mirrors.write().insert(id, (outbox, None));
+                let shared = shared.clone();
+                spawn(proc() mirror_client(id, st, shared, rx))
+            },
+            Err(_) if shared.shutting_down.load(SeqCst) => break,
+            Err(e) => log!(LogError, "", "{}", e)
+        }
+    }
+}
+
+fn mirror_client(id: Id, mut stream: TcpStream, shared: Shared, events: Receiver<Event>) {
+    let Shared { mirrors, stats, config, clock, .. } = shared;
+    let mut sc = stream.clone();
+    let (out, outgoing) = sync_channel(COMMAND_QUEUE);
+
+    /*
+     * The reader only waits for QUIT (or the connection to drop), ending with it. Anything else is
+     * answered READONLY, by the handler, which is the only one writing to the mirror.
+     */
+    spawn(proc() {
+        let mut buffer = [0u8, ..1024];
+        let mut lines = LineReader::new(MAX_LINE);
+        'reading: loop {
+            match sc.read(buffer) {
+                Ok(n) => for line in lines.feed(buffer.slice(0, n)).into_iter() {
+                    let quit = match line {
+                        Complete(ref line) => {
+                            trace(&id, "<-", line.as_slice());
+                            std::str::from_utf8(line.as_slice()).map_or(false, |line| match process_input(line) {
+                                Some(Quit) => true,
+                                _ => false
+                            })
+                        },
+                        TooLong => false
+                    };
+                    if quit || relay(&out, "READONLY\r\n".to_string()).is_err() { break 'reading }
+                },
+                Err(_) => break
+            }
+        }
+    });
+
+    loop {
+        let res = select! {
+            written = outgoing.recv_opt() => match written {
+                Ok(OutLines(lines)) => reply(&mut stream, &id, lines.as_bytes()),
+                /* A mirror is plain TCP, so there are no frames to answer */
+                Ok(OutFrame(..)) => Ok(()),
+                /* The reader is gone, so the mirror quit or dropped */
+                Err(()) => break
+            },
+            ev = events.recv_opt() => {
+                if ev.is_ok() { stats.queued_events.fetch_sub(1, SeqCst); }
+                match ev {
+                    Ok(ev @ Message(..)) => reply(&mut stream, &id, message_line(&config, clock.now_ms(), &ev).unwrap().as_bytes()),
+                    Ok(Closing(reason)) => {
+                        reply(&mut stream, &id, format!("SERVERCLOSING {}\r\n", reason).as_bytes()).ok();
+                        break;
+                    },
+                    Ok(_)  => Ok(()),
+                    Err(()) => Err(NotRegistered(id))
+                }
+            }
+        };
+        match res {
+            Ok(()) => (),
+            Err(e) => {
+                log!(LogInfo, client_tag(&id, stream.peer_name().ok().map(|addr| addr.ip)).as_slice(), "Mirror: {}. Disconnecting.", e);
+                break;
+            }
+        }
+    }
+
+    stream.close_read().ok();
+    mirrors.write().pop(&id);
+    while events.try_recv().is_ok() {
+        stats.queued_events.fetch_sub(1, SeqCst);
+    }
+}
+
+/*
+ * Server-sent events. The HTTP listener serves GET /rooms/<room> as an event stream of everything
+ * said in that room, so a plain web page can show it with EventSource. Each message is a "message"
+ * event whose data is a JSON object with room, name and text; a comment is sent every
+ * SSE_HEARTBEAT_S seconds so proxies keep the connection open and dead ones are noticed.
+ *
+ * It also serves GET /metrics, the server's counters for Prometheus to scrape.
+ */
+static SSE_HEARTBEAT_S: i64 = 15;
+
+#[deriving(Encodable)]
+struct SseMessage {
+    room: String,
+    name: String,
+    text: String
+}
+
+fn http_listener(mut acpt: TcpAcceptor, shared: Shared) {
+    loop {
+        match acpt.accept() {
+            Ok(st) => {
+                let id = shared.ids.lock().next_id();
+                let (mirrors, stats, config) = (shared.mirrors.clone(), shared.stats.clone(), shared.config.clone());
+                spawn(proc() sse_client(id, st, mirrors, stats, config))
+            },
+            Err(_) if shared.shutting_down.load(SeqCst) => break,
+            Err(e) => log!(LogError, "", "{}", e)
+        }
+    }
+}
+
+/*
+ * The path a request asks for: its target without any ?query or #fragment, percent-decoded, so
+ * a browser's /rooms/caf%C3%A9 is the room café. None if an escape is malformed or the result
+ * isn't UTF-8.
+ */
+fn decode_path(target: &str) -> Option<String> {
+    let end = target.find(|c: char| c == '?' || c == '#').unwrap_or(target.len());
+    let path = target.slice_to(end).as_bytes();
+    let mut out = Vec::new();
+    let mut i = 0;
+    while i < path.len() {
+        if path[i] == b'%' {
+            let digit = |at: uint| path.get(at).and_then(|&b| (b as char).to_digit(16));
+            match (digit(i + 1), digit(i + 2)) {
+                (Some(hi), Some(lo)) => out.push((hi * 16 + lo) as u8),
+                _ => return None
+            }
+            i += 3;
+        } else {
+            out.push(path[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8(out).ok()
+}
+
+fn sse_client(id: Id, mut stream: TcpStream, mirrors: Mirrors, stats: Arc<Stats>, config: Config) {
+    /* Only the request line matters; the headers are read and ignored */
+    let room = {
+        let mut reader = BufferedReader::new(stream.clone());
+        let request = match reader.read_line() {
+            Ok(line) => line,
+            Err(_)   => return
+        };
+        loop {
+            match reader.read_line() {
+                Ok(ref header) if header.as_slice().trim().is_empty() => break,
+                Ok(_)  => (),
+                Err(_) => return
+            }
+        }
+        let words: Vec<&str> = request.as_slice().words().collect();
+        let path = match words.as_slice() {
+            ["GET", target, ..] => match decode_path(target) {
+                Some(path) => path,
+                None => {
+                    write_all(&mut stream, b"HTTP/1.1 400 Bad Request\r\nContent-Length: 0\r\nConnection: close\r\n\r\n").ok();
+                    return;
+                }
+            },
+            _ => String::new()
+        };
+        match path.as_slice() {
+            path if path.starts_with("/rooms/") && path.len() > "/rooms/".len() => {
+                path.slice_from("/rooms/".len()).to_string()
+            },
+            "/metrics" => {
+                let body = stats.metrics();
+                let head = format!("HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n", body.len());
+                write_all(&mut stream, head.as_bytes()).and_then(|()| write_all(&mut stream, body.as_bytes())).ok();
+                return;
+            },
+            _ => {
+                write_all(&mut stream, b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n").ok();
+                return;
+            }
+        }
+    };
+
+    let head = b"HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\nCache-Control: no-cache\r\nConnection: close\r\n\r\n";
+    if write_all(&mut stream, head).is_err() { return }
+
+    let (outbox, events) = Outbox::new(id, &stream, &config);
+    mirrors.write().insert(id, (outbox, Some(fold(room.as_slice()))));
+    let mut timer = timer::Timer::new().unwrap();
+    let heartbeat = timer.periodic(Duration::seconds(SSE_HEARTBEAT_S));
+
+    loop {
+        let res = select! {
+            () = heartbeat.recv() => write_all(&mut stream, b": heartbeat\n\n"),
+            ev = events.recv_opt() => {
+                if ev.is_ok() { stats.queued_events.fetch_sub(1, SeqCst); }
+                match ev {
+                    Ok(RoomMessage(room, name, text)) => {
+                        let data = json::encode(&SseMessage { room: room, name: name, text: text });
+                        write_all(&mut stream, format!("event: message\ndata: {}\n\n", data).as_bytes())
+                    },
+                    Ok(Closing(_)) => break,
+                    _ => Ok(())
+                }
+            }
+        };
+        if res.is_err() { break }
+    }
+
+    mirrors.write().pop(&id);
+    while events.try_recv().is_ok() {
+        stats.queued_events.fetch_sub(1, SeqCst);
+    }
+}
+
+/*
+ * Client connections. A Transport is either a plain TCP stream carrying the line protocol, or a
+ * WebSocket (RFC 6455) whose text frames carry it: reading one gives the payloads of the frames,
+ * with each message ending a line, and each write is sent as one text frame. So the handler, the
+ * parser and the registry serve both alike.
+ *
+ * Only a client's handler writes to it, so frames never interleave. Whatever its reader needs
+ * written, the answer to a WebSocket ping included, goes to the handler as Outgoing.
+ */
+enum Outgoing {
+    /* Lines of the protocol, e.g. ERROR */
+    OutLines(String),
+    /* A WebSocket control frame: opcode and payload */
+    OutFrame(u8, Vec<u8>)
+}
+
+#[deriving(Clone)]
+struct Transport {
+    stream: TcpStream,
+    websocket: bool,
+    /* Where the pings and close frames read are answered from, if not here */
+    control: Option<SyncSender<Outgoing>>,
+    /* Bytes read from a WebSocket but not yet a whole frame, and payload not yet returned by read */
+    raw: Vec<u8>,
+    decoded: Vec<u8>,
+    /* Whether the last payload left a line unfinished, and whether it ended in \r */
+    line_open: bool,
+    after_cr: bool,
+    /* Whether the client asked for PROTO JSON, so lines written to it are sent as JSON objects */
+    json: Arc<AtomicBool>
+}
+
+/* The longest WebSocket frame accepted; longer lines than max_line are refused anyway */
+static WS_MAX_FRAME: uint = 1 << 20;
+
+/* What the handshake hashes the client's key with, from RFC 6455 */
+static WS_GUID: &'static str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+impl Transport {
+    fn tcp(stream: TcpStream) -> Transport {
+        Transport { stream: stream, websocket: false, control: None, raw: Vec::new(), decoded: Vec::new(), line_open: false,
+                    after_cr: false,
+                    json: Arc::new(AtomicBool::new(false)) }
+    }
+
+    fn websocket(stream: TcpStream) -> Transport {
+        Transport { websocket: true, ..Transport::tcp(stream) }
+    }
+
+    fn peer_name(&mut self) -> IoResult<SocketAddr> {
+        self.stream.peer_name()
+    }
+
+    fn set_read_timeout(&mut self, ms: Option<u64>) {
+        self.stream.set_read_timeout(ms)
+    }
+
+    fn close_read(&mut self) -> IoResult<()> {
+        self.stream.close_read()
+    }
+
+    fn send_frame(&mut self, opcode: u8, payload: &[u8]) -> IoResult<()> {
+        let mut frame = vec![0x80 | opcode];
+        match payload.len() {
+            n if n < 126   => frame.push(n as u8),
+            n if n < 65536 => {
+                frame.push(126);
+                frame.push((n >> 8) as u8);
+                frame.push(n as u8);
+            },
+            n => {
+                frame.push(127);
+                for i in range(0u, 8).rev() {
+                    frame.push((n as u64 >> (i * 8)) as u8);
+                }
+            }
+        }
+        frame.push_all(payload);
+        /* A frame can't be resumed part way, so coming up short is as bad as failing */
+        match self.stream.write(frame.as_slice()) {
+            Err(IoError { kind: ShortWrite(_), .. }) => Err(standard_error(BrokenPipe)),
+            res => res
+        }
+    }
+
+    /* Answer a control frame, through whoever writes to the client */
+    fn answer(&mut self, opcode: u8, payload: Vec<u8>) -> IoResult<()> {
+        match self.control {
+            Some(ref control) => control.send_opt(OutFrame(opcode, payload)).map_err(|_| standard_error(BrokenPipe)),
+            None => self.send_frame(opcode, payload.as_slice())
+        }
+    }
+
+    /*
+     * Take one whole frame off raw, if there is one. Data frames add their payload to decoded, with
+     * any line ending in a bare \n given its \r, pings are answered and a close frame ends the
+     * stream. Clients must mask their frames; one that doesn't is closed on with a protocol error.
+     */
+    fn decode(&mut self) -> IoResult<bool> {
+        let (fin, opcode, masked, payload, used) = {
+            let raw = self.raw.as_slice();
+            if raw.len() < 2 { return Ok(false) }
+            let (len, mut at) = match raw[1] & 0x7f {
+                126 if raw.len() >= 4  => ((raw[2] as uint << 8) | raw[3] as uint, 4u),
+                127 if raw.len() >= 10 => (range(2u, 10).fold(0u, |n, i| (n << 8) | raw[i] as uint), 10u),
+                126 | 127              => return Ok(false),
+                n                      => (n as uint, 2u)
+            };
+            if len > WS_MAX_FRAME { return Err(standard_error(InvalidInput)) }
+            let mask = if raw[1] & 0x80 != 0 { at += 4; Some(at - 4) } else { None };
+            if raw.len() < at + len { return Ok(false) }
+            let payload: Vec<u8> = raw.slice(at, at + len).iter().enumerate().map(|(i, &b)| match mask {
+                Some(m) => b ^ raw[m + i % 4],
+                None    => b
+            }).collect();
+            (raw[0] & 0x80 != 0, raw[0] & 0x0f, mask.is_some(), payload, at + len)
+        };
+        self.raw = self.raw.slice_from(used).to_vec();
+        if !masked {
+            /* Status 1002, protocol error */
+            self.answer(8, vec![0x03, 0xea]).ok();
+            return Err(standard_error(InvalidInput));
+        }
+        match opcode {
+            /* Continuation, text and binary frames all carry lines */
+            0 | 1 | 2 => {
+                for &b in payload.iter() {
+                    if b == b'\n' && !self.after_cr {
+                        self.decoded.push(b'\r');
+                    }
+                    self.decoded.push(b);
+                    self.after_cr = b == b'\r';
+                }
+                if !payload.is_empty() {
+                    self.line_open = *payload.last().unwrap() != b'\n';
+                }
+                if fin && self.line_open {
+                    if !self.after_cr {
+                        self.decoded.push(b'\r');
+                    }
+                    self.decoded.push(b'\n');
+                    self.line_open = false;
+                    self.after_cr = false;
+                }
+            },
+            8 => {
+                self.answer(8, payload).ok();
+                return Err(standard_error(EndOfFile));
+            },
+            9 => try!(self.answer(10, payload)),
+            _ => ()
+        }
+        Ok(true)
+    }
+}
+
+impl Reader for Transport {
+    fn read(&mut self, buf: &mut [u8]) -> IoResult<uint> {
+        if !self.websocket { return self.stream.read(buf) }
+        while self.decoded.is_empty() {
+            if !try!(self.decode()) {
+                let mut chunk = [0u8, ..4096];
+                let n = try!(self.stream.read(chunk));
+                self.raw.push_all(chunk.slice_to(n));
+            }
+        }
+        let n = std::cmp::min(buf.len(), self.decoded.len());
+        for (to, from) in buf.iter_mut().zip(self.decoded.iter()) {
+            *to = *from;
+        }
+        self.decoded = self.decoded.slice_from(n).to_vec();
+        Ok(n)
+    }
+}
+
+impl Writer for Transport {
+    fn write(&mut self, buf: &[u8]) -> IoResult<()> {
+        let encoded = if self.json.load(SeqCst) { Some(lines_to_json(buf)) } else { None };
+        let buf = encoded.as_ref().map_or(buf, |json| json.as_bytes());
+        if self.websocket { self.send_frame(1, buf) } else { self.stream.write(buf) }
+    }
+}
+
+/*
+ * The JSON protocol. Each line is an object whose "type" is the command or reply, in lowercase, and
+ * whose other members are its arguments, named as in VERBS and REPLIES (see doc_fields). Every
+ * value is a string, except that repeated arguments are a list of them. Commands may also give the
+ * arguments as one "args" string, as they would be written after the verb.
+ */
+fn doc_fields(spec: &str) -> (&'static str, Vec<(String, bool)>) {
+    let sep = if spec.contains("\\t") { "\\t" } else { " " };
+    let mut fields: Vec<(String, bool)> = Vec::new();
+    for token in spec.split_str(sep).filter(|t| !t.is_empty()) {
+        if token.trim_chars(']') == "..." {
+            match fields.pop() { Some((name, _)) => fields.push((name, true)), None => () }
+            continue;
+        }
+        let name = match (token.find('<'), token.find('>')) {
+            (Some(open), Some(close)) if open < close => token.slice(open + 1, close),
+            _ => continue
+        };
+        /* A choice of words, like <connected|spoke>, is the state of something */
+        fields.push((if name.contains_char('|') { "state".to_string() } else { name.to_string() }, false));
+    }
+    (if sep == " " { " " } else { "\t" }, fields)
+}
+
+/* Write lines of the text protocol as JSON lines. Lines of replies not in REPLIES keep their text */
+fn lines_to_json(buf: &[u8]) -> String {
+    let mut out = String::new();
+    for line in String::from_utf8_lossy(buf).as_slice().lines_any().filter(|l| !l.is_empty()) {
+        let (verb, rest) = match line.find(' ') {
+            Some(i) => (line.slice_to(i), line.slice_from(i + 1)),
+            None    => (line, "")
+        };
+        let mut obj = TreeMap::new();
+        obj.insert("type".to_string(), json::String(verb.to_ascii_lower()));
+        match REPLIES.iter().find(|r| r.reply == verb) {
+            Some(doc) => {
+                let (sep, fields) = doc_fields(doc.args);
+                let mut rest = rest;
+                for (i, &(ref name, many)) in fields.iter().enumerate() {
+                    let value = if many {
+                        let values = rest.split_str(sep).filter(|v| !v.is_empty()).map(|v| json::String(v.to_string())).collect();
+                        rest = "";
+                        json::List(values)
+                    } else if i + 1 == fields.len() {
+                        /* The last argument is the rest of the line, spaces and all */
+                        if rest.is_empty() { continue }
+                        json::String(rest.to_string())
+                    } else {
+                        while rest.starts_with(sep) {
+                            rest = rest.slice_from(sep.len());
+                        }
+                        if rest.is_empty() { break }
+                        let (value, after) = match rest.find_str(sep) {
+                            Some(at) => (rest.slice_to(at), rest.slice_from(at + sep.len())),
+                            None     => (rest, "")
+                        };
+                        rest = after;
+                        json::String(value.to_string())
+                    };
+                    obj.insert(name.clone(), value);
+                }
+            },
+            None => if !rest.is_empty() { obj.insert("text".to_string(), json::String(rest.to_string())); }
+        }
+        out.push_str(json::Object(obj).to_string().as_slice());
+        out.push_str("\n");
+    }
+    out
+}
+
+/* Turn a JSON command back into the line it stands for, or None if it isn't one */
+fn json_to_line(line: &[u8]) -> Option<Vec<u8>> {
+    let obj = match json::from_str(String::from_utf8_lossy(line).as_slice()) {
+        Ok(json::Object(obj)) => obj,
+        _ => return None
+    };
+    let text = |key: &str| obj.find(&key.to_string()).map(|v| match *v {
+        json::String(ref s) => s.clone(),
+        ref other => other.to_string()
+    });
+    let verb = match text("type") { Some(verb) => verb.into_ascii_upper(), None => return None };
+    let mut out = verb.clone();
+    match text("args") {
+        Some(args) => {
+            out.push_str(" ");
+            out.push_str(args.as_slice());
+        },
+        None => match VERBS.iter().find(|v| v.verb == verb.as_slice()) {
+            Some(doc) => for &(ref name, _) in doc_fields(doc.args).val1().iter() {
+                match text(name.as_slice()) {
+                    /* A value with spaces in it can only be the last argument, so it goes after ':' */
+                    Some(ref value) if value.as_slice().contains_char(' ') || value.as_slice().starts_with(":") => {
+                        out.push_str(" :");
+                        out.push_str(value.as_slice());
+                        break;
+                    },
+                    Some(value) => {
+                        out.push_str(" ");
+                        out.push_str(value.as_slice());
+                    },
+                    None => break
+                }
+            },
+            None => ()
+        }
+    }
+    /* JSON strings may hold any character, but the line they are turned into may not */
+    if has_control(out.as_slice()) { None } else { Some(out.into_bytes()) }
+}
+
+/* SHA-1, which the WebSocket handshake calls for. Nothing else should use it */
+fn sha1(data: &[u8]) -> Vec<u8> {
+    fn rotl(x: u32, n: uint) -> u32 { (x << n) | (x >> (32 - n)) }
+    let mut h = [0x67452301u32, 0xEFCDAB89, 0x98BADCFE, 0x10325476, 0xC3D2E1F0];
+    let mut msg = data.to_vec();
+    msg.push(0x80);
+    while msg.len() % 64 != 56 { msg.push(0) }
+    for i in range(0u, 8).rev() {
+        msg.push(((data.len() as u64 * 8) >> (i * 8)) as u8);
+    }
+    for block in msg.as_slice().chunks(64) {
+        let mut w = [0u32, ..80];
+        for i in range(0u, 16) {
+            w[i] = (block[4*i] as u32 << 24) | (block[4*i+1] as u32 << 16) | (block[4*i+2] as u32 << 8) | block[4*i+3] as u32;
+        }
+        for i in range(16u, 80) {
+            w[i] = rotl(w[i-3] ^ w[i-8] ^ w[i-14] ^ w[i-16], 1);
+        }
+        let (mut a, mut b, mut c, mut d, mut e) = (h[0], h[1], h[2], h[3], h[4]);
+        for i in range(0u, 80) {
+            let (f, k) = match i / 20 {
+                0 => ((b & c) | (!b & d), 0x5A827999u32),
+                1 => (b ^ c ^ d, 0x6ED9EBA1),
+                2 => ((b & c) | (b & d) | (c & d), 0x8F1BBCDC),
+                _ => (b ^ c ^ d, 0xCA62C1D6)
+            };
+            let t = rotl(a, 5) + f + e + k + w[i];
+            e = d;
+            d = c;
+            c = rotl(b, 30);
+            b = a;
+            a = t;
+        }
+        for (x, y) in h.iter_mut().zip([a, b, c, d, e].iter()) {
+            *x += *y;
+        }
+    }
+    let mut digest = Vec::new();
+    for x in h.iter() {
+        for i in range(0u, 4).rev() {
+            digest.push((*x >> (i * 8)) as u8);
+        }
+    }
+    digest
+}
+
+/*
+ * The opening handshake: an HTTP GET asking to upgrade, answered with 101 and the client's key
+ * hashed as the RFC says. The request is read a byte at a time so nothing after it is taken.
+ */
+fn websocket_handshake(stream: &mut TcpStream) -> IoResult<()> {
+    let mut request = Vec::new();
+    while !request.as_slice().ends_with(b"\r\n\r\n") {
+        if request.len() > MAX_LINE {
+            return Err(standard_error(InvalidInput));
+        }
+        request.push(try!(stream.read_byte()));
+    }
+    let request = String::from_utf8_lossy(request.as_slice()).into_string();
+    let header = |name: &str| request.as_slice().lines_any().skip(1).filter_map(|line| match line.find(':') {
+        Some(i) if line.slice_to(i).trim().to_ascii_lower().as_slice() == name => Some(line.slice_from(i + 1).trim()),
+        _ => None
+    }).next();
+    /* Only a GET asking to upgrade to a WebSocket is one */
+    let upgrade = request.as_slice().starts_with("GET ")
+                  && header("upgrade").map_or(false, |value| value.to_ascii_lower().as_slice() == "websocket");
+    match header("sec-websocket-key") {
+        Some(key) if upgrade => {
+            let accept = sha1(format!("{}{}", key, WS_GUID).as_bytes()).as_slice().to_base64(STANDARD);
+            write_all(stream, format!("HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+                                       Sec-WebSocket-Accept: {}\r\n\r\n", accept).as_bytes())
+        },
+        _ => {
+            write_all(stream, b"HTTP/1.1 400 Bad Request\r\nContent-Length: 0\r\nConnection: close\r\n\r\n").ok();
+            Err(standard_error(InvalidInput))
+        }
+    }
+}
+
+fn websocket_listener(mut acpt: TcpAcceptor, server: Server) {
+    loop {
+        match acpt.accept() {
+            Ok(mut st) => {
+                /* The handshake waits on the client, so it gets a task of its own */
+                let server = server.clone();
+                spawn(proc() {
+                    match websocket_handshake(&mut st) {
+                        Ok(()) => {
+                            let id = server.shared.ids.lock().next_id();
+                            server.admit(Transport::websocket(st), id)
+                        },
+                        Err(e) => log!(LogInfo, "", "WebSocket handshake failed: {}", e)
+                    }
+                })
+            },
+            Err(_) if server.shared.shutting_down.load(SeqCst) => break,
+            Err(e) => log!(LogError, "", "{}", e)
+        }
+    }
+}
+
+/*
+ * Give a client that has logged in to an account the account's name, and join it to the rooms it
+ * gets: the default ones if this is its first name, and the ones the account was last in. Only
+ * then is the client's account (logged_in) set; if somebody else has the name, it is left alone.
+ * The lines to reply with, starting with "<greeting> <name>" or NAMEINUSE.
+ */
+fn log_in(shared: &Shared, id: &Id, conn: uint, name: &mut String, logged_in: &mut Option<String>,
+          account: &str, greeting: &str) -> Result<String, ServerError> {
+    let (clients, rooms, aliases, accounts) = (&shared.clients, &shared.rooms, &shared.aliases, &shared.accounts);
+    let claimed = clients.write().claim(id, account);
+    match claimed {
+        NameGiven(given) => {
+            let first = name.is_empty();
+            named(shared, conn, name.as_slice(), given.as_slice());
+            *name = given;
+            *logged_in = Some(account.to_string());
+            let mut lines = format!("{} {}\r\n", greeting, name);
+            if first {
+                lines.push_str(auto_join(rooms, clients, aliases, shared.config.auto_join.as_slice(), id).as_slice());
+            }
+            let saved = accounts.read().rooms(account);
+            lines.push_str(auto_join(rooms, clients, aliases, saved.as_slice(), id).as_slice());
+            save_rooms(accounts, account, rooms, id);
+            Ok(lines)
+        },
+        NameTaken => Ok(format!("NAMEINUSE {}\r\n", account)),
+        Unregistered => Err(NotRegistered(*id))
+    }
+}
+
+/* Remember the rooms a logged in client is in, to join them again next time */
+fn save_rooms(accounts: &AccountStore, account: &str, rooms: &Rooms, id: &Id) {
+    let joined = rooms_of(&*rooms.read(), id);
+    match accounts.write().set_rooms(account, joined) {
+        Ok(()) => (),
+        Err(e) => log!(LogError, "", "Saving the rooms of {}: {}", account, e)
+    }
+}
+
+/* Tell everyone that a client with the name old (empty if it had none) now has the name new */
+fn named(shared: &Shared, conn: uint, old: &str, new: &str) {
+    if old.is_empty() {
+        send_all(&shared.clients, &*shared.stats, Joined(new.to_string(), conn));
+    } else if old != new {
+        send_all(&shared.clients, &*shared.stats, Renamed(old.to_string(), new.to_string(), conn));
+    }
+    saw(&shared.seen, shared.clock.now_ms(), new, Connected);
+}
+
+/*
+ * Split off the first word of a line, returning it and the (trimmed) rest.
+ */
+fn split_word<'a>(line: &'a str) -> (&'a str, &'a str) {
+    let line = line.trim();
+    match line.find(' ') {
+        Some(i) => (line.slice_to(i), line.slice_from(i + 1).trim()),
+        None    => (line, "")
+    }
+}
+
+/*
+ * Whether a name matches a WHO pattern, in which * matches any run of characters and ? any one.
+ * Both are folded first, so case doesn't matter.
+ */
+fn glob_match(pattern: &str, name: &str) -> bool {
+    fn matches(p: &[char], n: &[char]) -> bool {
+        match (p, n) {
+            ([], [])                       => true,
+            (['*', ..prest], _)            => matches(prest, n) || (!n.is_empty() && matches(p, n.slice_from(1))),
+            (['?', ..prest], [_, ..nrest]) => matches(prest, nrest),
+            ([pc, ..prest], [nc, ..nrest]) => pc == nc && matches(prest, nrest),
+            _                              => false
+        }
+    }
+    let (pattern, name): (Vec<char>, Vec<char>) = (fold(pattern).as_slice().chars().collect(), fold(name).as_slice().chars().collect());
+    matches(pattern.as_slice(), name.as_slice())
+}
+
+fn announce(clients: &Clients, stats: &Stats, lines: Vec<String>) {
+    send_all(clients, stats, Announce(lines));
+}
+
+/*
+ * Scheduled announcements, added from the console with a cron-style schedule in local time:
+ * minute, hour, day of month, month and day of week (0 is Sunday). Each field is *, a number, a
+ * range a-b, any of those followed by /step, or a comma separated list of them. Unlike cron, the
+ * day of month and day of week must both match.
+ */
+static CRON_FIELDS: [(uint, uint), ..5] = [(0, 59), (0, 23), (1, 31), (1, 12), (0, 6)];
+
+struct Schedule {
+    id: uint,
+    spec: String,
+    /* For each field, whether each value from 0 up to the field's maximum matches */
+    fields: Vec<Vec<bool>>,
+    text: String
+}
+
+type Schedules = Arc<Mutex<Vec<Schedule>>>;
+
+fn parse_cron_field(field: &str, min: uint, max: uint) -> Option<Vec<bool>> {
+    let mut allowed = Vec::from_elem(max + 1, false);
+    for part in field.split(',') {
+        let (range, step) = match part.find('/') {
+            Some(i) => (part.slice_to(i), from_str::<uint>(part.slice_from(i + 1))),
+            None    => (part, Some(1))
+        };
+        let (lo, hi) = if range == "*" {
+            (Some(min), Some(max))
+        } else {
+            match range.find('-') {
+                Some(i) => (from_str::<uint>(range.slice_to(i)), from_str::<uint>(range.slice_from(i + 1))),
+                None    => {
+                    let n = from_str::<uint>(range);
+                    (n, n)
+                }
+            }
+        };
+        match (lo, hi, step) {
+            (Some(lo), Some(hi), Some(step)) if min <= lo && lo <= hi && hi <= max && step > 0 => {
+                for v in range_step_inclusive(lo, hi, step) {
+                    *allowed.get_mut(v) = true;
+                }
+            },
+            _ => return None
+        }
+    }
+    Some(allowed)
+}
+
+fn parse_cron(spec: &[&str]) -> Option<Vec<Vec<bool>>> {
+    let mut fields = Vec::new();
+    for (field, &(min, max)) in spec.iter().zip(CRON_FIELDS.iter()) {
+        match parse_cron_field(*field, min, max) {
+            Some(allowed) => fields.push(allowed),
+            None          => return None
+        }
+    }
+    Some(fields)
+}
+
+impl Schedule {
+    fn matches(&self, tm: &time::Tm) -> bool {
+        let now = [tm.tm_min, tm.tm_hour, tm.tm_mday, tm.tm_mon + 1, tm.tm_wday];
+        self.fields.iter().zip(now.iter()).all(|(allowed, &v)| allowed[v as uint])
+    }
+}
+
+/* Wakes at the start of every minute and sends whatever is due, until told to stop */
+fn scheduler(clients: Clients, stats: Arc<Stats>, schedules: Schedules, clock: SharedClock, stop: Receiver<()>) {
+    let mut timer = timer::Timer::new().unwrap();
+    loop {
+        let minute = timer.oneshot(Duration::milliseconds(60000 - (clock.now_ms() % 60000) as i64));
+        select! {
+            () = minute.recv() => (),
+            _ = stop.recv_opt() => break
+        }
+        let tm = time::at(time::Timespec::new((clock.now_ms() / 1000) as i64, 0));
+        for s in schedules.lock().iter().filter(|s| s.matches(&tm)) {
+            announce(&clients, &*stats, vec![s.text.clone()]);
+            log!(LogInfo, "", "Scheduled announcement {} ({}) sent: {}", s.id, s.spec, s.text);
+        }
+    }
+}
+
+/*
+ * The operator console. Reads admin commands from the server's stdin, one per line, so whoever runs
+ * the server in the foreground can manage it without another client. When stdin is closed (the
+ * server runs detached), the console simply ends. It also ends with the server, though a read of
+ * stdin can't be cut short, so one already waiting for a line has to get it (or the end of input).
+ */
+fn console(clients: Clients, stats: Arc<Stats>, seen: LastSeen, schedules: Schedules,
+           maintenance: Arc<RWLock<Maintenance>>, bans: Bans, shutting_down: Arc<AtomicBool>) {
+    let mut input = stdin();
+    for line in input.lines() {
+        let line = match line {
+            Ok(line) if !shutting_down.load(SeqCst) => line,
+            _ => break
+        };
+        match split_word(line.as_slice()) {
+            ("", _) => (),
+            ("who", _) => {
+                let c = clients.read();
+                for (id, &(_, ref name)) in c.by_id.iter() {
+                    println!("{} #{} {}", id, c.conn(id), if name.is_empty() { "(no name)" } else { name.as_slice() });
+                }
+            },
+            ("kick", args) => {
+                let (target, reason) = split_word(args);
+                if target.is_empty() {
+                    println!("usage: kick <name|id|#connection> [reason]");
+                } else {
+                    let reason = if reason.is_empty() { KICK_REASON } else { reason };
+                    let mut n = 0u;
+                    let c = clients.read();
+                    for (id, &(ref client, ref name)) in c.by_id.iter() {
+                        if fold(name.as_slice()) == fold(target) || id.to_string().as_slice() == target
+                           || format!("#{}", c.conn(id)).as_slice() == target {
+                            stats.deliver(client, Kick(reason.to_string()));
+                            n += 1;
+                        }
+                    }
+                    println!("Kicked {} client(s)", n);
+                }
+            },
+            ("announce", "") => println!("usage: announce <text>"),
+            ("announce", text) => announce(&clients, &*stats, vec![text.to_string()]),
+            ("bigannounce", "") => println!("usage: bigannounce <text>"),
+            ("bigannounce", text) if text.char_len() > BANNER_MAX_CHARS => {
+                println!("Too long for a banner (at most {} characters)", BANNER_MAX_CHARS);
+            },
+            ("bigannounce", text) => announce(&clients, &*stats, banner(text)),
+            ("stats", _) => {
+                let c = clients.read();
+                println!("uptime:      {}s", stats.uptime_s());
+                println!("clients:     {} ({} named)", c.by_id.len(), c.by_name.len());
+                println!("connections: {}", stats.connections.load(SeqCst));
+                println!("broadcasts:  {}", stats.broadcasts.load(SeqCst));
+                println!("registered:  {} ({} handlers)", stats.registered.load(SeqCst), stats.handlers.load(SeqCst));
+                println!("queued:      {} events", stats.queued_events.load(SeqCst));
+                println!("dropped:     {} events", stats.dropped_events.load(SeqCst));
+                println!("errors:      {} malformed lines", stats.parse_errors.load(SeqCst));
+                println!("traffic:     {} bytes in, {} out", BYTES_IN.load(SeqCst), BYTES_OUT.load(SeqCst));
+                println!("last seen:   {} names", seen.read().len());
+                for &(verb, n, [p50, p95, p99]) in stats.latency_percentiles().iter() {
+                    println!("{:<12} p50 {}us, p95 {}us, p99 {}us ({} samples)", verb, p50, p95, p99, n);
+                }
+            },
+            ("check", _) => {
+                /* Counters against the structures they count, to catch entries left behind */
+                let (registered, handlers) = (stats.registered.load(SeqCst), stats.handlers.load(SeqCst));
+                let c = clients.read();
+                let entries = c.by_id.len();
+                if registered == entries && handlers == entries {
+                    println!("OK: {} registry entries, {} handlers", entries, handlers);
+                } else {
+                    println!("MISMATCH: {} registry entries, {} counted, {} handlers", entries, registered, handlers);
+                }
+                /* Every name in the index must belong to the client it points to */
+                let stale = c.by_name.iter().filter(|&(name, id)| {
+                    c.by_id.find(id).map_or(true, |&(_, ref n)| fold(n.as_slice()) != *name)
+                }).count();
+                let named = c.by_id.values().filter(|&&(_, ref n)| !n.is_empty()).count();
+                if stale > 0 || named != c.by_name.len() {
+                    println!("MISMATCH: {} names indexed, {} stale, {} named clients", c.by_name.len(), stale, named);
+                }
+            },
+            ("schedule", args) => {
+                let mut spec = Vec::new();
+                let mut rest = args;
+                for _ in range(0u, 5) {
+                    let (field, more) = split_word(rest);
+                    spec.push(field);
+                    rest = more;
+                }
+                match parse_cron(spec.as_slice()) {
+                    Some(fields) if !rest.is_empty() => {
+                        let mut schedules = schedules.lock();
+                        let id = schedules.iter().map(|s| s.id).max().unwrap_or(0) + 1;
+                        let spec = spec.connect(" ");
+                        println!("Scheduled announcement {} added ({}): {}", id, spec, rest);
+                        schedules.push(Schedule { id: id, spec: spec, fields: fields, text: rest.to_string() });
+                    },
+                    _ => println!("usage: schedule <minute> <hour> <day> <month> <weekday> <text>")
+                }
+            },
+            ("schedules", _) => {
+                for s in schedules.lock().iter() {
+                    println!("{} {} {}", s.id, s.spec, s.text);
+                }
+            },
+            ("unschedule", n) => {
+                let mut schedules = schedules.lock();
+                match from_str::<uint>(n).and_then(|n| schedules.iter().position(|s| s.id == n)) {
+                    Some(i) => {
+                        let s = schedules.remove(i).unwrap();
+                        println!("Scheduled announcement {} removed ({}): {}", s.id, s.spec, s.text);
+                    },
+                    None => println!("usage: unschedule <n>, with n from schedules")
+                }
+            },
+            ("maintenance", args) => match split_word(args) {
+                ("on", message) => {
+                    let message = if message.is_empty() { MAINTENANCE_MESSAGE } else { message };
+                    maintenance.write().message = Some(message.to_string());
+                    for &(ref client, _) in clients.read().by_id.values() {
+                        stats.deliver(client, MaintenanceOn(message.to_string()));
+                    }
+                    println!("Maintenance mode on: {}", message);
+                },
+                ("off", _) => {
+                    maintenance.write().message = None;
+                    println!("Maintenance mode off");
+                },
+                _ => {
+                    let m = maintenance.read();
+                    let allow: Vec<String> = m.allow.iter().map(|ip| ip.to_string()).collect();
+                    match m.message {
+                        Some(ref message) => println!("Maintenance mode on: {}", message),
+                        None              => println!("Maintenance mode off")
+                    }
+                    println!("allowed: {}", if allow.is_empty() { "(nobody)".to_string() } else { allow.connect(" ") });
+                    println!("usage: maintenance on [message] | maintenance off");
+                }
+            },
+            ("allow", addr) => match from_str::<IpAddr>(addr) {
+                Some(ip) => {
+                    let mut m = maintenance.write();
+                    if !m.allow.contains(&ip) { m.allow.push(ip); }
+                },
+                None => println!("usage: allow <ip address>")
+            },
+            ("disallow", addr) => match from_str::<IpAddr>(addr) {
+                Some(ip) => maintenance.write().allow.retain(|a| *a != ip),
+                None     => println!("usage: disallow <ip address>")
+            },
+            ("bans", _) => {
+                let bans: Vec<String> = bans.read().iter().map(|ip| ip.to_string()).collect();
+                println!("banned: {}", if bans.is_empty() { "(nobody)".to_string() } else { bans.connect(" ") });
+            },
+            ("unban", addr) => match from_str::<IpAddr>(addr) {
+                Some(ip) => bans.write().retain(|a| *a != ip),
+                None     => println!("usage: unban <ip address>")
+            },
+            ("help", _) => {
+                println!("commands: who, kick <name|id|#connection> [reason], announce <text>, bigannounce <text>, stats, check,");
+                println!("          schedule <minute> <hour> <day> <month> <weekday> <text>, schedules, unschedule <n>,");
+                println!("          maintenance [on [message]|off], allow <ip>, disallow <ip>, bans, unban <ip>");
+            },
+            (cmd, _) => println!("Unknown command: {} (try help)", cmd)
+        }
+    }
+}
+
+/*
+ * Soak testing. Synthetic clients inside the server process that connect to it over TCP like any
+ * other client and keep broadcasting, every interval_ms milliseconds, for as long as the server
+ * runs. Watch the process's memory and the console's stats while they do.
+ */
+fn soak_client(addr: SocketAddr, n: uint, interval_ms: i64) {
+    let mut stream = match TcpStream::connect(addr.ip.to_string().as_slice(), addr.port) {
+        Ok(stream) => stream,
+        Err(e)     => {
+            log!(LogWarn, "", "Soak client {} cannot connect: {}", n, e);
+            return;
+        }
+    };
+
+    /* Everything the server sends has to be read, or our TCP buffers fill and the server backs up */
+    let mut rs = stream.clone();
+    spawn(proc() {
+        let mut buf = [0u8, ..4096];
+        while rs.read(buf).is_ok() {}
+    });
+
+    if write_all(&mut stream, format!("NAME soak{}\r\n", n).as_bytes()).is_err() { return }
+    let mut seq = 0u;
+    loop {
+        timer::sleep(Duration::milliseconds(interval_ms));
+        seq += 1;
+        /* Now and then ask for the user list too, which walks the whole registry */
+        let line = if seq % 10 == 0 { "WHO\r\n".to_string() } else { format!("BROADCAST soak {} {}\r\n", n, seq) };
+        if write_all(&mut stream, line.as_bytes()).is_err() {
+            log!(LogWarn, "", "Soak client {} lost its connection.", n);
+            return;
+        }
+    }
+}
+
+/*
+ * Server settings. A configuration file holds "key = value" lines, a small subset of TOML: values
+ * are integers, booleans or double-quoted strings, and # starts a comment. Settings the file
+ * leaves out keep their defaults, and the command line can override any of them.
+ */
+#[deriving(Clone)]
+pub struct Config {
+    pub address: String,
+    pub port: u16,
+    /* 0 for no limit */
+    pub max_clients: uint,
+    /* The longest line a client may send, not counting the \r\n */
+    pub max_line: uint,
+    /* Seconds without any input before a client is disconnected, 0 for never */
+    pub idle_timeout: u64,
+    /* Seconds without hearing from a client before it is sent a keepalive PING, 0 for never */
+    pub keepalive_interval: u64,
+    /* Seconds a client has to answer a keepalive PING before it is disconnected */
+    pub keepalive_grace: u64,
+    /* Give clients asking for a taken name that name with a free _1, _2, ... suffix, instead of NAMEINUSE */
+    pub suffix_names: bool,
+    /* Events that may wait for a client before slow_clients applies */
+    pub outgoing_queue: uint,
+    pub slow_clients: SlowPolicy,
+    /* The password OPER takes to make a client an operator. No operators if there is none */
+    pub oper_password: Option<String>,
+    /* Where accounts are kept. No REGISTER without one */
+    pub accounts_file: Option<Path>,
+    /* Where room aliases are kept. Without one they last until the server stops */
+    pub room_aliases_file: Option<Path>,
+    /* The message of the day, sent to every client as it connects and reread on REHASH */
+    pub motd_file: Option<Path>,
+    /* Rooms every client joins when it first takes a name */
+    pub auto_join: Vec<String>,
+    /* How many broadcasts are kept for HISTORY, and how many of them a new client is sent */
+    pub history_size: uint,
+    pub history_on_connect: uint,
+    /*
+     * Flood protection: tokens per second (a broadcast costs 2, WHO half of one, 0 for no limit), how
+     * many can be saved up, and how many throttled commands get a client disconnected (0 for never)
+     */
+    pub rate_limit: f64,
+    pub rate_burst: f64,
+    pub throttle_strikes: uint,
+    /* Verbs answered with ERR_DISABLED instead of being handled */
+    pub disabled: Vec<String>,
+    /* The least severe log lines that are written */
+    pub log_level: Level,
+    /* Templates for the FROM, PRIVFROM and SAYFROM lines, and what {server} is in them */
+    pub format_from: String,
+    pub format_privfrom: String,
+    pub format_sayfrom: String,
+    pub server_tag: String,
+    /* Ports for the read-only mirror, the HTTP event streams and WebSocket clients, if any */
+    pub mirror_port: Option<u16>,
+    pub http_port: Option<u16>,
+    pub websocket_port: Option<u16>,
+    /* Where every client's raw input is recorded, if anywhere */
+    pub record_dir: Option<Path>,
+    /* Print every line sent and received */
+    pub trace_protocol: bool
+}
+
+impl Config {
+    pub fn new() -> Config {
+        Config {
+            address: "127.0.0.1".to_string(),
+            port: 8090,
+            max_clients: 0,
+            max_line: MAX_LINE,
+            idle_timeout: 0,
+            keepalive_interval: 0,
+            keepalive_grace: KEEPALIVE_GRACE,
+            suffix_names: false,
+            outgoing_queue: OUTGOING_QUEUE,
+            slow_clients: SlowDrop,
+            oper_password: None,
+            accounts_file: None,
+            room_aliases_file: None,
+            motd_file: None,
+            auto_join: Vec::new(),
+            history_size: HISTORY_SIZE,
+            history_on_connect: HISTORY_ON_CONNECT,
+            rate_limit: RATE_LIMIT,
+            rate_burst: RATE_BURST,
+            throttle_strikes: THROTTLE_STRIKES,
+            disabled: Vec::new(),
+            log_level: LogInfo,
+            format_from: FORMAT_FROM.to_string(),
+            format_privfrom: FORMAT_PRIVFROM.to_string(),
+            format_sayfrom: FORMAT_SAYFROM.to_string(),
+            server_tag: SERVER_TAG.to_string(),
+            mirror_port: None,
+            http_port: None,
+            websocket_port: None,
+            record_dir: None,
+            trace_protocol: false
+        }
+    }
+
+    pub fn load(path: &Path) -> Result<Config, String> {
+        let text = match File::open(path).read_to_string() {
+            Ok(text) => text,
+            Err(e)   => return Err(format!("{}: {}", path.display(), e))
+        };
+        let mut config = Config::new();
+        for (n, line) in text.as_slice().lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with("#") { continue }
+            let res = match line.find('=') {
+                Some(i) => match config_value(line.slice_from(i + 1)) {
+                    Some(value) => config.set(line.slice_to(i).trim(), value),
+                    None        => Err(format!("malformed value: {}", line))
+                },
+                None => Err(format!("expected key = value: {}", line))
+            };
+            match res {
+                Ok(()) => (),
+                Err(e) => return Err(format!("{}:{}: {}", path.display(), n + 1, e))
+            }
+        }
+        Ok(config)
+    }
+
+    /* Change one setting, given as it would be written in the file but without quotes */
+    pub fn set(&mut self, key: &str, value: &str) -> Result<(), String> {
+        match key {
+            "address"      => self.address = value.to_string(),
+            "port"         => self.port = try!(config_parse(key, value)),
+            "max_clients"  => self.max_clients = try!(config_parse(key, value)),
+            "max_line"     => match try!(config_parse(key, value)) {
+                0 => return Err("max_line must be at least 1".to_string()),
+                n => self.max_line = n
+            },
+            "idle_timeout" => self.idle_timeout = try!(config_parse(key, value)),
+            "keepalive_interval" => self.keepalive_interval = try!(config_parse(key, value)),
+            "keepalive_grace" => match try!(config_parse(key, value)) {
+                0 => return Err("keepalive_grace must be at least 1".to_string()),
+                n => self.keepalive_grace = n
+            },
+            "suffix_names" => self.suffix_names = try!(config_parse(key, value)),
+            "outgoing_queue" => match try!(config_parse(key, value)) {
+                0 => return Err("outgoing_queue must be at least 1".to_string()),
+                n => self.outgoing_queue = n
+            },
+            "slow_clients" => self.slow_clients = try!(config_parse(key, value)),
+            "oper_password" => self.oper_password = Some(value.to_string()),
+            "accounts_file" => self.accounts_file = Some(Path::new(value)),
+            "room_aliases_file" => self.room_aliases_file = Some(Path::new(value)),
+            "motd_file" => self.motd_file = Some(Path::new(value)),
+            "auto_join" => {
+                let rooms: Vec<String> = value.split(',').map(|r| r.trim()).filter(|r| !r.is_empty()).map(|r| r.to_string()).collect();
+                if rooms.iter().any(|r| r.as_slice().contains_char(' ')) {
+                    return Err(format!("room names can't contain spaces: {}", value));
+                }
+                self.auto_join = rooms;
+            },
+            "history_size" => self.history_size = try!(config_parse(key, value)),
+            "history_on_connect" => self.history_on_connect = try!(config_parse(key, value)),
+            "rate_limit" => self.rate_limit = try!(config_parse(key, value)),
+            "rate_burst" => self.rate_burst = try!(config_parse(key, value)),
+            "throttle_strikes" => self.throttle_strikes = try!(config_parse(key, value)),
+            "disabled_verbs" => {
+                let verbs: Vec<String> = value.split(',').map(|v| v.trim()).filter(|v| !v.is_empty())
+                                              .map(|v| v.to_string().into_ascii_upper()).collect();
+                match verbs.iter().find(|v| v.as_slice() == "QUIT" || !VERBS.iter().any(|d| d.verb == v.as_slice())) {
+                    Some(v) => return Err(format!("cannot disable {}", v)),
+                    None    => self.disabled = verbs
+                }
+            },
+            "log_level" => self.log_level = try!(config_parse(key, value)),
+            "format_from" => self.format_from = try!(message_format(key, "FROM", value)),
+            "format_privfrom" => self.format_privfrom = try!(message_format(key, "PRIVFROM", value)),
+            "format_sayfrom" => self.format_sayfrom = try!(message_format(key, "SAYFROM", value)),
+            "server_tag" => self.server_tag = value.to_string(),
+            "mirror_port" => self.mirror_port = Some(try!(config_parse(key, value))),
+            "http_port" => self.http_port = Some(try!(config_parse(key, value))),
+            "websocket_port" => self.websocket_port = Some(try!(config_parse(key, value))),
+            "record_dir" => self.record_dir = Some(Path::new(value)),
+            "trace_protocol" => self.trace_protocol = try!(config_parse(key, value)),
+            _ => return Err(format!("unknown setting: {}", key))
+        }
+        Ok(())
+    }
+
+    /*
+     * Settings that are fine one by one but not together. Checked once everything is set, since the
+     * command line may fix what the file got wrong.
+     */
+    pub fn check(&self) -> Result<(), String> {
+        if self.history_on_connect > self.history_size {
+            return Err(format!("history_on_connect ({}) is more than history_size ({}) keeps",
+                               self.history_on_connect, self.history_size));
+        }
+        if self.rate_limit > 0.0 && self.rate_burst < Broadcast(String::new()).cost() {
+            return Err(format!("rate_burst ({}) is less than a BROADCAST costs, so nobody could broadcast",
+                               self.rate_burst));
+        }
+        /* Every listener needs a port of its own, and port 0 is a different free port each time */
+        let ports = [("port", Some(self.port)), ("mirror_port", self.mirror_port), ("http_port", self.http_port),
+                     ("websocket_port", self.websocket_port)];
+        for (i, &(setting, port)) in ports.iter().enumerate() {
+            match ports.slice_from(i + 1).iter().find(|&&(_, other)| port.is_some() && port != Some(0) && other == port) {
+                Some(&(other, _)) => return Err(format!("{} and {} are both port {}", setting, other, port.unwrap())),
+                None => ()
+            }
+        }
+        Ok(())
+    }
+
+    /* What the server runs with, a setting or a feature per line, for the log at startup */
+    pub fn summary(&self) -> Vec<(&'static str, String)> {
+        fn or(value: uint, zero: &str) -> String {
+            if value == 0 { zero.to_string() } else { value.to_string() }
+        }
+        fn file(path: &Option<Path>, none: &str) -> String {
+            path.as_ref().map_or(none.to_string(), |path| path.display().to_string())
+        }
+        fn port(port: Option<u16>) -> String {
+            port.map_or("off".to_string(), |port| port.to_string())
+        }
+        let policy = match self.slow_clients { SlowDrop => "drop", SlowDisconnect => "disconnect" };
+        let flood = if self.rate_limit > 0.0 {
+            format!("{} per second, burst {}, disconnect after {}", self.rate_limit, self.rate_burst,
+                    or(self.throttle_strikes, "never"))
+        } else {
+            "off".to_string()
+        };
+        vec![("listen", format!("{}:{}", self.address, self.port)),
+             ("max_clients", or(self.max_clients, "no limit")),
+             ("max_line", self.max_line.to_string()),
+             ("idle_timeout", or(self.idle_timeout as uint, "never")),
+             ("keepalive", if self.keepalive_interval == 0 { "off".to_string() }
+                           else { format!("{}s, {}s to answer", self.keepalive_interval, self.keepalive_grace) }),
+             ("slow_clients", format!("{} after {} queued events", policy, self.outgoing_queue)),
+             ("flood_protection", flood),
+             ("operators", (if self.oper_password.is_some() { "on" } else { "off" }).to_string()),
+             ("accounts", file(&self.accounts_file, "off")),
+             ("room_aliases", file(&self.room_aliases_file, "in memory")),
+             ("motd", file(&self.motd_file, "none")),
+             ("auto_join", if self.auto_join.is_empty() { "none".to_string() } else { self.auto_join.connect(",") }),
+             ("history", format!("{} kept, {} on connect", self.history_size, self.history_on_connect)),
+             ("disabled_verbs", if self.disabled.is_empty() { "none".to_string() } else { self.disabled.connect(",") }),
+             ("mirror", port(self.mirror_port)),
+             ("http", port(self.http_port)),
+             ("websocket", port(self.websocket_port)),
+             ("recording", file(&self.record_dir, "off")),
+             ("trace_protocol", (if self.trace_protocol { "on" } else { "off" }).to_string()),
+             ("log_level", self.log_level.to_string())]
+    }
+}
+
+/* The value part of a line, without its quotes and any trailing comment */
+fn config_value<'a>(raw: &'a str) -> Option<&'a str> {
+    let raw = raw.trim();
+    if raw.starts_with("\"") {
+        let rest = raw.slice_from(1);
+        rest.find('"').and_then(|end| {
+            let after = rest.slice_from(end + 1).trim();
+            if after.is_empty() || after.starts_with("#") { Some(rest.slice_to(end)) } else { None }
+        })
+    } else {
+        let value = match raw.find('#') { Some(i) => raw.slice_to(i), None => raw }.trim();
+        if value.is_empty() { None } else { Some(value) }
+    }
+}
+
+fn config_parse<T: FromStr>(key: &str, value: &str) -> Result<T, String> {
+    match from_str(value) {
+        Some(v) => Ok(v),
+        None    => Err(format!("invalid value for {}: {}", key, value))
+    }
+}
+
+/*
+ * The server. Cloning a Server gives another handle to the same server, so one clone can run() it
+ * while another shuts it down.
+ */
+#[deriving(Clone)]
+pub struct Server {
+    acceptor: TcpAcceptor,
+    addr: SocketAddr,
+    record_dir: Option<Path>,
+    /* The mirror, HTTP and WebSocket listeners, closed along with the main one */
+    listeners: Arc<Mutex<Vec<TcpAcceptor>>>,
+    /* Told when the server shuts down: the tasks waiting for something that would never come then */
+    stop: Arc<Mutex<Vec<Sender<()>>>>,
+    schedules: Schedules,
+    shared: Shared
+}
+
+impl Server {
+    /*
+     * Listen on addr, e.g. "127.0.0.1:8090", with the default settings. Clients can connect as soon
+     * as this returns, but are only served once run() is called. Port 0 picks a free port; addr()
+     * tells which.
+     */
+    pub fn bind(addr: &str) -> IoResult<Server> {
+        let mut config = Config::new();
+        match from_str::<SocketAddr>(addr) {
+            Some(sa) => {
+                config.address = sa.ip.to_string();
+                config.port = sa.port;
+            },
+            None => return Err(standard_error(InvalidInput))
+        }
+        Server::with_config(config)
+    }
+
+    /* Listen on the configured address and port, with the configured limits */
+    pub fn with_config(config: Config) -> IoResult<Server> {
+        Server::with_clock(config, Arc::new(box SystemClock as Box<Clock + Send + Sync>))
+    }
+
+    /* The same, telling the time by clock */
+    fn with_clock(config: Config, clock: SharedClock) -> IoResult<Server> {
+        set_log_level(config.log_level.clone());
+        if config.trace_protocol {
+            set_trace_protocol(true);
+        }
+        let mut acceptor = try!(TcpListener::bind(config.address.as_slice(), config.port).listen());
+        let addr = try!(acceptor.socket_name());
+        acceptor.set_timeout(None);
+        let accounts = try!(Accounts::open(config.accounts_file.clone()));
+        let aliases = try!(RoomAliases::open(config.room_aliases_file.clone()));
+        let motd = try!(read_motd(&config.motd_file));
+        for &(setting, ref value) in config.summary().iter() {
+            log!(LogInfo, "", "config {} = {}", setting, value);
+        }
+        let mut registry = Registry::new();
+        registry.suffix_names = config.suffix_names;
+        Ok(Server {
+            acceptor: acceptor,
+            addr: addr,
+            record_dir: config.record_dir.clone(),
+            listeners: Arc::new(Mutex::new(Vec::new())),
+            stop: Arc::new(Mutex::new(Vec::new())),
+            schedules: Arc::new(Mutex::new(Vec::new())),
+            shared: Shared {
+                clients: Arc::new(RWLock::new(registry)),
+                stats: Arc::new(Stats::new(clock.clone())),
+                seen: Arc::new(RWLock::new(HashMap::new())),
+                maintenance: Arc::new(RWLock::new(Maintenance { message: None, allow: Vec::new() })),
+                bans: Arc::new(RWLock::new(Vec::new())),
+                accounts: Arc::new(RWLock::new(accounts)),
+                mirrors: Arc::new(RWLock::new(HashMap::new())),
+                rooms: Arc::new(RWLock::new(HashMap::new())),
+                aliases: Arc::new(RWLock::new(aliases)),
+                backlog: Arc::new(RWLock::new(RingBuf::new())),
+                motd: Arc::new(RWLock::new(motd)),
+                config: config,
+                ids: Arc::new(Mutex::new(box TimeIdGenerator::new(clock.clone()) as Box<IdGenerator + Send>)),
+                clock: clock,
+                shutting_down: Arc::new(AtomicBool::new(false))
+            }
+        })
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /* Record the raw input of every client connecting from now on to <dir>/<id>.rec */
+    pub fn record_to(&mut self, dir: Path) {
+        self.record_dir = Some(dir);
+    }
+
+    /* Listen on another port of the same address, until shutdown() */
+    fn listen(&self, port: u16) -> IoResult<TcpAcceptor> {
+        let acpt = try!(TcpListener::bind(self.addr.ip.to_string().as_slice(), port).listen());
+        self.listeners.lock().push(acpt.clone());
+        Ok(acpt)
+    }
+
+    /* Serve the read-only mirror of broadcasts on another port of the same address */
+    pub fn mirror(&self, port: u16) -> IoResult<()> {
+        let acpt = try!(self.listen(port));
+        let shared = self.shared.clone();
+        spawn(proc() mirror_listener(acpt, shared));
+        Ok(())
+    }
+
+    /*
+     * Serve clients over WebSocket on another port of the same address. They are clients like any
+     * other, each text frame carrying lines of the protocol.
+     */
+    pub fn websocket(&self, port: u16) -> IoResult<()> {
+        let acpt = try!(self.listen(port));
+        let server = self.clone();
+        spawn(proc() websocket_listener(acpt, server));
+        Ok(())
+    }
+
+    /* Serve room event streams over HTTP on another port of the same address */
+    pub fn http(&self, port: u16) -> IoResult<()> {
+        let acpt = try!(self.listen(port));
+        let shared = self.shared.clone();
+        spawn(proc() http_listener(acpt, shared));
+        Ok(())
+    }
+
+    /* Read operator commands from stdin */
+    pub fn console(&self) {
+        let s = &self.shared;
+        let (clients, stats, seen) = (s.clients.clone(), s.stats.clone(), s.seen.clone());
+        let (schedules, maintenance, bans) = (self.schedules.clone(), s.maintenance.clone(), s.bans.clone());
+        let shutting_down = s.shutting_down.clone();
+        spawn(proc() console(clients, stats, seen, schedules, maintenance, bans, shutting_down));
+    }
+
+    /* Start n synthetic clients, each broadcasting every interval_ms milliseconds */
+    pub fn soak(&self, n: uint, interval_ms: i64) {
+        let addr = self.addr;
+        for k in range(1, n + 1) {
+            spawn(proc() soak_client(addr, k, interval_ms));
+        }
+    }
+
+    /* Accept and serve clients until shutdown() is called */
+    pub fn run(&self) {
+        let (clients, stats, schedules) = (self.shared.clients.clone(), self.shared.stats.clone(), self.schedules.clone());
+        let clock = self.shared.clock.clone();
+        let (stop_tx, stop) = channel();
+        self.stop.lock().push(stop_tx);
+        spawn(proc() scheduler(clients, stats, schedules, clock, stop));
+
+        let mut acpt = self.acceptor.clone();
+        loop {
+            match acpt.accept() {
+                Ok(st) => {
+                    let id = self.shared.ids.lock().next_id();
+                    self.admit(Transport::tcp(st), id)
+                },
+                Err(_) if self.shared.shutting_down.load(SeqCst) => break,
+                Err(e) => {
+                    log!(LogError, "", "{}", e);
+                }
+            }
+        }
+
+        /* Give the handlers a moment to write what they still have and close their connections */
+        let mut waited = 0;
+        while self.shared.stats.handlers.load(SeqCst) > 0 && waited < SHUTDOWN_GRACE_MS {
+            timer::sleep(Duration::milliseconds(100));
+            waited += 100;
+        }
+        let left = self.shared.stats.handlers.load(SeqCst);
+        if left > 0 {
+            log!(LogWarn, "", "{} client(s) still connected after {}ms, closing anyway", left, SHUTDOWN_GRACE_MS);
+            for &(ref client, _) in self.shared.clients.read().by_id.values() {
+                client.close();
+            }
+            for &(ref mirror, _) in self.shared.mirrors.read().values() {
+                mirror.close();
+            }
+        }
+    }
+
+    /* Take on a new connection, unless it has to be turned away, and start its handler */
+    fn admit(&self, mut st: Transport, id: Id) {
+        let peer = st.peer_name().ok().map(|addr| addr.ip);
+        let address = peer.map_or("-".to_string(), |ip| ip.to_string());
+        let s = &self.shared;
+        match s.maintenance.read().refuse(peer) {
+            Some(message) => {
+                write_all(&mut st, format!("MAINTENANCE {}\r\n", message).as_bytes()).ok();
+                log!(LogInfo, address.as_slice(), "refused for maintenance");
+                return;
+            },
+            None => ()
+        }
+
+        if peer.map_or(false, |ip| s.bans.read().contains(&ip)) {
+            write_all(&mut st, format!("KICKED {}\r\n", BAN_REASON).as_bytes()).ok();
+            log!(LogInfo, address.as_slice(), "refused, banned");
+            return;
+        }
+
+        let full = s.config.max_clients > 0 && s.clients.read().by_id.len() >= s.config.max_clients;
+        if full {
+            write_all(&mut st, b"SERVERFULL\r\n").ok();
+            log!(LogWarn, address.as_slice(), "refused, server full");
+            return;
+        }
+
+        let conn = s.stats.connections.fetch_add(1, SeqCst) + 1;
+        let (outbox, rx) = Outbox::new(id, &st.stream, &s.config);
+        s.clients.write().insert(id, conn, outbox, s.clock.now_ms());
+        s.stats.registered.fetch_add(1, SeqCst);
+        s.stats.handlers.fetch_add(1, SeqCst);
+
+        let rec = self.record_dir.as_ref().and_then(|dir| open_recording(dir, &id));
+        let shared = s.clone();
+        spawn(proc() handle_client(id, conn, st, shared, rx, rec))
+    }
+
+    /* Stop accepting, and disconnect everyone who is connected with SERVERCLOSING */
+    pub fn shutdown(&self) {
+        self.shutdown_with(SHUTDOWN_REASON)
+    }
+
+    pub fn shutdown_with(&self, reason: &str) {
+        let s = &self.shared;
+        if s.shutting_down.swap(true, SeqCst) { return }
+        log!(LogInfo, "", "Shutting down: {}", reason);
+        self.acceptor.clone().close_accept().ok();
+        for acpt in self.listeners.lock().iter_mut() {
+            acpt.close_accept().ok();
+        }
+        for stop in self.stop.lock().iter() {
+            stop.send_opt(()).ok();
+        }
+        for &(ref client, _) in s.clients.read().by_id.values() {
+            send_closing(s.stats.clone(), client, reason);
+        }
+        for &(ref mirror, _) in s.mirrors.read().values() {
+            send_closing(s.stats.clone(), mirror, reason);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Server,Config,Clock,MockClock,Stats,IdGenerator,SequentialIdGenerator,Recording,MAX_LINE,lines_to_json,fold,glob_match};
+    use super::{VERBS,REPLIES,decode_path,process_input,tokenize,json_to_line,MAX_NAME,Transport};
+    use std::io::{TcpListener,Listener,Acceptor};
+    use super::{Who,Name,Msg,Say};
+    use std::io::{TcpStream,BufferedReader,TempDir,File};
+    use std::sync::Arc;
+
+    /* A client of a test server: what it reads, and where it writes */
+    struct Client {
+        reader: BufferedReader<TcpStream>,
+        writer: TcpStream
+    }
+
+    impl Client {
+        fn connect(server: &Server) -> Client {
+            let addr = server.addr();
+            let stream = TcpStream::connect(addr.ip.to_string().as_slice(), addr.port).unwrap();
+            let mut rs = stream.clone();
+            rs.set_read_timeout(Some(5000));
+            Client { reader: BufferedReader::new(rs), writer: stream }
+        }
+
+        fn send(&mut self, line: &str) {
+            self.writer.write_str(format!("{}\r\n", line).as_slice()).unwrap();
+        }
+
+        /* The next line, without its \r\n */
+        fn line(&mut self) -> String {
+            let line = self.reader.read_line().unwrap();
+            assert!(line.as_slice().ends_with("\r\n"), "not terminated by \\r\\n: {}", line);
+            line.as_slice().slice_to(line.len() - 2).to_string()
+        }
+    }
+
+    /* The default settings, but on a free port */
+    fn config() -> Config {
+        let mut config = Config::new();
+        config.port = 0;
+        config
+    }
+
+    /* Serve until the returned handle is shut down */
+    fn start(server: Server) -> Server {
+        let runner = server.clone();
+        spawn(proc() runner.run());
+        server
+    }
+
+    #[test]
+    fn name_and_broadcast_round_trip() {
+        let server = start(Server::bind("127.0.0.1:0").unwrap());
+        let mut c = Client::connect(&server);
+        c.send("NAME alice");
+        c.send("BROADCAST hello  there");
+        assert_eq!(c.line().as_slice(), "JOINED alice 1");
+        assert_eq!(c.line().as_slice(), "FROM alice hello  there");
+        server.shutdown();
+        assert_eq!(c.line().as_slice(), "SERVERCLOSING Server shutting down");
+    }
+
+    #[test]
+    fn seen_tells_time_by_the_server_clock() {
+        let clock = MockClock::new(1000000);
+        let server = start(Server::with_clock(config(), Arc::new(box clock.clone() as Box<Clock + Send + Sync>)).unwrap());
+        let mut c = Client::connect(&server);
+        c.send("NAME alice");
+        assert_eq!(c.line().as_slice(), "JOINED alice 1");
+        clock.advance(90000);
+        c.send("SEEN alice");
+        assert_eq!(c.line().as_slice(), "SEEN alice 90 connected");
+        server.shutdown();
+    }
+
+    #[test]
+    fn connections_are_recorded_under_their_ids() {
+        let dir = TempDir::new("dikuchat").unwrap();
+        let mut server = Server::with_config(config()).unwrap();
+        server.record_to(dir.path().clone());
+        *server.shared.ids.lock() = box SequentialIdGenerator::new() as Box<IdGenerator + Send>;
+        let server = start(server);
+        let mut a = Client::connect(&server);
+        a.send("NAME alice");
+        assert_eq!(a.line().as_slice(), "JOINED alice 1");
+        let mut b = Client::connect(&server);
+        b.send("NAME bob");
+        assert_eq!(b.line().as_slice(), "JOINED bob 2");
+        assert!(dir.path().join("0000000000001.rec").exists());
+        assert!(dir.path().join("0000000000002.rec").exists());
+        server.shutdown();
+    }
+
+    /* The reads a recording holds */
+    fn recorded(path: &Path) -> Vec<Vec<u8>> {
+        let mut f = File::open(path).unwrap();
+        let mut reads = Vec::new();
+        loop {
+            match f.read_be_u32() {
+                Ok(len) => reads.push(f.read_exact(len as uint).unwrap()),
+                Err(_)  => return reads
+            }
+        }
+    }
+
+    #[test]
+    fn recordings_hide_passwords_split_over_reads() {
+        let dir = TempDir::new("dikuchat").unwrap();
+        let path = dir.path().join("test.rec");
+        let mut rec = Recording::new(Some(File::create(&path).unwrap()), MAX_LINE);
+        rec.record(b"WHO\r\nOPER hun");
+        rec.record(b"ter2\r\nLOGIN alice se");
+        rec.record(b"cret\r\nBROADCAST hi\r\n");
+        rec.finish();
+        drop(rec);
+        assert_eq!(recorded(&path), vec![b"WHO\r\nOPER ***".to_vec(), b"****\r\nLOGIN alice **".to_vec(),
+                                         b"****\r\nBROADCAST hi\r\n".to_vec()]);
+    }
+
+    #[test]
+    fn json_keeps_the_last_argument_verbatim() {
+        assert_eq!(lines_to_json(b"SAYFROM lobby alice hello  there \r\n").as_slice(),
+                   "{\"name\":\"alice\",\"room\":\"lobby\",\"text\":\"hello  there \",\"type\":\"sayfrom\"}\n");
+    }
+
+    #[test]
+    fn names_fold_in_any_script() {
+        assert_eq!(fold("Alice").as_slice(), "alice");
+        assert_eq!(fold("ÉMILE").as_slice(), fold("émile").as_slice());
+        assert_eq!(fold("ΣΟΦΙΑ").as_slice(), fold("σοφια").as_slice());
+    }
+
+    #[test]
+    fn listeners_need_ports_of_their_own() {
+        let mut c = Config::new();
+        c.set("http_port", "8091").unwrap();
+        c.set("websocket_port", "8091").unwrap();
+        assert_eq!(c.check(), Err("http_port and websocket_port are both port 8091".to_string()));
+        c.set("websocket_port", "0").unwrap();
+        c.set("mirror_port", "0").unwrap();
+        c.set("port", "0").unwrap();
+        assert_eq!(c.check(), Ok(()));
+    }
+
+    #[test]
+    fn patterns_match_whole_names() {
+        assert!(glob_match("al*", "Alice"));
+        assert!(glob_match("*ce", "alice"));
+        assert!(glob_match("a?ice", "alice"));
+        assert!(glob_match("*", ""));
+        assert!(!glob_match("al", "alice"));
+        assert!(!glob_match("a?", "a"));
+    }
+
+    /* A line each verb accepts */
+    static SAMPLES: &'static [(&'static str, &'static str)] = &[
+        ("QUIT", ""), ("WHO", ""), ("WHOX", ""), ("NAME", "alice"), ("BROADCAST", "hi"), ("MSG", "bob hi"),
+        ("JOIN", "lobby"), ("PART", "lobby"), ("LIST", ""), ("SAY", "lobby hi"), ("TOPIC", "lobby"),
+        ("ROLL", "2d6"), ("CHOOSE", "tea|coffee"), ("SEEN", "bob"), ("LAG", ""), ("PONG", "abc"),
+        ("OPER", "s3cret"), ("KICK", "bob"), ("BAN", "bob"), ("REGISTER", "alice s3cret"),
+        ("LOGIN", "alice s3cret"), ("HISTORY", "5"), ("ALIAS", "hall lobby"), ("RENAMEROOM", "lobby hall"),
+        ("REHASH", ""), ("ANONYMOUS", "lobby on"), ("PROTO", "json"), ("HELP", "say")
+    ];
+
+    #[test]
+    fn every_documented_verb_is_understood() {
+        assert_eq!(SAMPLES.len(), VERBS.len());
+        for v in VERBS.iter() {
+            let &(_, args) = SAMPLES.iter().find(|&&(verb, _)| verb == v.verb).expect(v.verb);
+            let parsed = process_input(format!("{} {}", v.verb, args).as_slice());
+            assert_eq!(parsed.map(|m| m.verb()), Some(v.verb));
+        }
+    }
+
+    #[test]
+    fn documented_replies_exist() {
+        for v in VERBS.iter() {
+            for reply in v.replies.iter() {
+                assert!(REPLIES.iter().any(|r| r.reply == *reply), "{} replies {}, which isn't documented", v.verb, reply);
+            }
+        }
+    }
+
+    #[test]
+    fn empty_lines_have_no_verb() {
+        for line in ["", "   ", "\t"].iter() {
+            let (verb, args) = tokenize(*line);
+            assert_eq!(verb.as_slice(), "");
+            assert_eq!(args.len(), 0);
+            assert!(process_input(*line).is_none());
+        }
+    }
+
+    #[test]
+    fn a_bare_verb_has_no_arguments() {
+        let (verb, args) = tokenize("WHO");
+        assert_eq!((verb.as_slice(), args.len()), ("WHO", 0));
+        assert!(match process_input("WHO") { Some(Who(None)) => true, _ => false });
+    }
+
+    #[test]
+    fn verbs_are_matched_in_any_case() {
+        assert_eq!(tokenize("wHoX").val0().as_slice(), "WHOX");
+        assert!(match process_input("say lobby hi") {
+            Some(Say(ref room, ref text)) => room.as_slice() == "lobby" && text.as_slice() == "hi",
+            _ => false
+        });
+    }
+
+    #[test]
+    fn repeated_spaces_separate_once_but_stay_in_text() {
+        let (verb, args) = tokenize("SAY  lobby \t hello   there  ");
+        assert_eq!(verb.as_slice(), "SAY");
+        assert_eq!(args.word(0), Some("lobby"));
+        assert_eq!(args.text(1), Some("hello   there"));
+    }
+
+    #[test]
+    fn a_colon_argument_runs_to_the_end() {
+        let (_, args) = tokenize("MSG bob :hi  there ");
+        assert_eq!(args.len(), 2);
+        assert_eq!(args.text(1), Some("hi  there "));
+        assert_eq!(tokenize("SAY lobby ::-)").val1().text(1), Some(":-)"));
+        assert!(match process_input("NAME :alice") { Some(Name(ref name)) => name.as_slice() == "alice", _ => false });
+        /* Only an argument starting with ':' is one, and the verb never is */
+        assert_eq!(tokenize("SAY lobby a:b c").val1().word(1), Some("a:b"));
+        assert_eq!(tokenize(":WHO").val0().as_slice(), ":WHO");
+    }
+
+    #[test]
+    fn a_lone_colon_is_an_empty_argument() {
+        let (_, args) = tokenize("SAY lobby :");
+        assert_eq!(args.len(), 2);
+        assert_eq!(args.word(1), None);
+        assert_eq!(args.text(1), None);
+        assert!(process_input("SAY lobby :").is_none());
+        assert!(process_input("NAME :").is_none());
+    }
+
+    #[test]
+    fn arguments_are_counted() {
+        for line in ["MSG bob", "SAY lobby", "JOIN", "JOIN lobby hall", "NAME alice bob", "ALIAS hall",
+                     "ALIAS hall lobby more", "ROLL", "ANONYMOUS lobby", "PROTO JSON TEXT"].iter() {
+            assert!(process_input(*line).is_none(), "{} was accepted", line);
+        }
+        assert!(match process_input("MSG bob hi there") {
+            Some(Msg(ref to, ref text)) => to.as_slice() == "bob" && text.as_slice() == "hi there",
+            _ => false
+        });
+    }
+
+    #[test]
+    fn names_are_counted_in_characters() {
+        let longest = String::from_char(MAX_NAME, 'é');
+        assert!(match process_input(format!("NAME {}", longest).as_slice()) {
+            Some(Name(ref name)) => *name == longest,
+            _ => false
+        });
+        assert!(process_input(format!("NAME {}e", longest).as_slice()).is_none());
+        assert!(process_input(format!("REGISTER {}e s3cret", longest).as_slice()).is_none());
+    }
+
+    #[test]
+    fn control_characters_are_refused() {
+        for line in ["BROADCAST hi\rFROM mallory pwned", "NAME eve\nJOINED bob", "SAY lobby a\x00b",
+                     "TOPIC lobby :x\r\nTOPICCHANGED lobby bob y"].iter() {
+            assert!(process_input(*line).is_none(), "{} was accepted", line.escape_default());
+        }
+        assert!(process_input("SAY lobby a\tb").is_some());
+    }
+
+    #[test]
+    fn json_values_cannot_hold_control_characters() {
+        assert!(json_to_line(b"{\"type\":\"broadcast\",\"text\":\"hi\\r\\nFROM mallory pwned\"}").is_none());
+        assert!(json_to_line(b"{\"type\":\"name\",\"name\":\"eve\\u0000\"}").is_none());
+        assert_eq!(json_to_line(b"{\"type\":\"broadcast\",\"text\":\"hi there\"}"), Some(b"BROADCAST :hi there".to_vec()));
+    }
+
+    /* A WebSocket transport on a connection nobody reads, for decoding frames put in by hand */
+    fn websocket() -> Transport {
+        let acpt = TcpListener::bind("127.0.0.1", 0).listen().unwrap();
+        let addr = acpt.socket_name().unwrap();
+        Transport::websocket(TcpStream::connect(addr.ip.to_string().as_slice(), addr.port).unwrap())
+    }
+
+    /* A short masked text frame, or a continuation of one */
+    fn frame(first: bool, fin: bool, payload: &[u8]) -> Vec<u8> {
+        let mask = [1u8, 2, 3, 4];
+        let mut frame = vec![(if fin { 0x80 } else { 0 }) | (if first { 1 } else { 0 }), 0x80 | payload.len() as u8];
+        frame.push_all(&mask);
+        frame.extend(payload.iter().enumerate().map(|(i, &b)| b ^ mask[i % 4]));
+        frame
+    }
+
+    fn decoded(frames: &[Vec<u8>]) -> Vec<u8> {
+        let mut t = websocket();
+        for f in frames.iter() {
+            t.raw.push_all(f.as_slice());
+        }
+        while t.decode().unwrap() {}
+        t.decoded
+    }
+
+    #[test]
+    fn websocket_frames_end_lines() {
+        assert_eq!(decoded(&[frame(true, true, b"hi\n")]), b"hi\r\n".to_vec());
+        assert_eq!(decoded(&[frame(true, true, b"hi")]), b"hi\r\n".to_vec());
+        assert_eq!(decoded(&[frame(true, true, b"a\nb\r\n"), frame(true, true, b"c")]), b"a\r\nb\r\nc\r\n".to_vec());
+        assert_eq!(decoded(&[frame(true, false, b"hi\r"), frame(false, true, b"\n")]), b"hi\r\n".to_vec());
+        assert_eq!(decoded(&[frame(true, false, b"WH"), frame(false, true, b"O")]), b"WHO\r\n".to_vec());
+    }
+
+    #[test]
+    fn unmasked_websocket_frames_are_refused() {
+        let mut t = websocket();
+        t.raw.push_all(&[0x81, 2, b'h', b'i']);
+        assert!(t.decode().is_err());
+    }
+
+    #[test]
+    fn metrics_export_latency_percentiles() {
+        let stats = Stats::new(Arc::new(box MockClock::new(0) as Box<Clock + Send + Sync>));
+        for us in range(1u64, 101) {
+            stats.record_latency("SAY", us);
+        }
+        let metrics = stats.metrics();
+        assert!(metrics.as_slice().contains("dikuchat_commands_total{verb=\"SAY\"} 100\n"));
+        for &(quantile, us) in [("0.5", 50u), ("0.95", 95), ("0.99", 99)].iter() {
+            let line = format!("dikuchat_command_latency_microseconds{{verb=\"SAY\",quantile=\"{}\"}} {}\n", quantile, us);
+            assert!(metrics.as_slice().contains(line.as_slice()), "{} missing", line);
+        }
+    }
+
+    #[test]
+    fn request_paths_are_decoded() {
+        assert_eq!(decode_path("/rooms/caf%C3%A9"), Some("/rooms/café".to_string()));
+        assert_eq!(decode_path("/rooms/lobby?since=0"), Some("/rooms/lobby".to_string()));
+        assert_eq!(decode_path("/rooms/lobby#top"), Some("/rooms/lobby".to_string()));
+        assert_eq!(decode_path("/metrics"), Some("/metrics".to_string()));
+        assert_eq!(decode_path("/rooms/%E9"), None);
+        assert_eq!(decode_path("/rooms/%zz"), None);
+        assert_eq!(decode_path("/rooms/lobby%2"), None);
+    }
+}